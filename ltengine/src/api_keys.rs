@@ -0,0 +1,234 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub key: String,
+    /// Free-form description of who the key belongs to
+    #[serde(default)]
+    pub name: String,
+    /// Character limit per request (None = server limit)
+    pub char_limit: Option<usize>,
+    /// Requests per minute (None = server limit)
+    pub req_limit: Option<u32>,
+    /// Origins allowed to use this key (empty = any origin)
+    #[serde(default)]
+    pub origins: Vec<String>,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Creation time (unix timestamp)
+    #[serde(default)]
+    pub created: u64,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+/// Settings of a new key, or changes to an existing one
+#[derive(Clone, Debug, Default, Deserialize, clap::Args)]
+pub struct ApiKeySettings {
+    /// Description of the key
+    #[arg(long)]
+    pub name: Option<String>,
+    /// Character limit per request
+    #[arg(long)]
+    pub char_limit: Option<usize>,
+    /// Requests per minute
+    #[arg(long)]
+    pub req_limit: Option<u32>,
+    /// Origins allowed to use the key (comma separated)
+    #[arg(long = "origin", value_delimiter = ',')]
+    pub origins: Option<Vec<String>>,
+    #[arg(skip)]
+    pub enabled: Option<bool>,
+}
+
+impl ApiKeySettings {
+    fn apply(self, key: &mut ApiKey) {
+        if let Some(name) = self.name {
+            key.name = name;
+        }
+        if let Some(limit) = self.char_limit {
+            key.char_limit = (limit > 0).then_some(limit);
+        }
+        if let Some(limit) = self.req_limit {
+            key.req_limit = (limit > 0).then_some(limit);
+        }
+        if let Some(origins) = self.origins {
+            key.origins = origins;
+        }
+        if let Some(enabled) = self.enabled {
+            key.enabled = enabled;
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum KeyError {
+    /// Unknown or disabled key
+    Invalid,
+    /// The key cannot be used from this origin
+    Origin,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct KeysFile {
+    #[serde(default)]
+    keys: Vec<ApiKey>,
+}
+
+/// API keys persisted in a JSON file
+pub struct ApiKeyStore {
+    path: PathBuf,
+    keys: RwLock<BTreeMap<String, ApiKey>>,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+impl ApiKeyStore {
+    /// Loads the keys from `path`, starting with no keys if the file does not exist yet
+    pub fn load(path: &Path) -> Result<ApiKeyStore> {
+        let file: KeysFile = if path.exists() {
+            let data = std::fs::read_to_string(path)
+                .with_context(|| format!("Unable to read API keys file: {}", path.display()))?;
+            serde_json::from_str(&data)
+                .with_context(|| format!("Invalid API keys file: {}", path.display()))?
+        } else {
+            KeysFile::default()
+        };
+
+        Ok(ApiKeyStore {
+            path: path.to_path_buf(),
+            keys: RwLock::new(file.keys.into_iter().map(|k| (k.key.clone(), k)).collect()),
+        })
+    }
+
+    fn save(&self, keys: &BTreeMap<String, ApiKey>) -> Result<()> {
+        let file = KeysFile { keys: keys.values().cloned().collect() };
+        let data = serde_json::to_string_pretty(&file)?;
+
+        // Write to a temporary file first so that the store is never left half written
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, data)
+            .with_context(|| format!("Unable to write API keys file: {}", tmp.display()))?;
+        std::fs::rename(&tmp, &self.path)
+            .with_context(|| format!("Unable to write API keys file: {}", self.path.display()))?;
+        Ok(())
+    }
+
+    pub fn list(&self) -> Vec<ApiKey> {
+        self.keys.read().unwrap().values().cloned().collect()
+    }
+
+    pub fn create(&self, settings: ApiKeySettings) -> Result<ApiKey> {
+        let mut key = ApiKey {
+            key: uuid::Uuid::new_v4().to_string(),
+            name: String::new(),
+            char_limit: None,
+            req_limit: None,
+            origins: Vec::new(),
+            enabled: true,
+            created: now(),
+        };
+        settings.apply(&mut key);
+
+        let mut keys = self.keys.write().unwrap();
+        keys.insert(key.key.clone(), key.clone());
+        self.save(&keys)?;
+        Ok(key)
+    }
+
+    pub fn update(&self, key: &str, settings: ApiKeySettings) -> Result<ApiKey> {
+        let mut keys = self.keys.write().unwrap();
+        let entry = keys.get_mut(key).ok_or_else(|| anyhow!("Unknown API key: {}", key))?;
+        settings.apply(entry);
+        let updated = entry.clone();
+        self.save(&keys)?;
+        Ok(updated)
+    }
+
+    pub fn revoke(&self, key: &str) -> Result<()> {
+        let mut keys = self.keys.write().unwrap();
+        keys.remove(key).ok_or_else(|| anyhow!("Unknown API key: {}", key))?;
+        self.save(&keys)?;
+        Ok(())
+    }
+
+    /// Checks that `key` is enabled and can be used from `origin`
+    pub fn authorize(&self, key: &str, origin: Option<&str>) -> Result<ApiKey, KeyError> {
+        let entry = self.keys.read().unwrap()
+            .get(key)
+            .filter(|k| k.enabled)
+            .cloned()
+            .ok_or(KeyError::Invalid)?;
+
+        if !entry.origins.is_empty() && !origin.is_some_and(|o| entry.origins.iter().any(|allowed| origin_matches(o, allowed))) {
+            return Err(KeyError::Origin);
+        }
+
+        Ok(entry)
+    }
+
+    /// Whether an enabled key is restricted to `origin`
+    pub fn origin_allowed(&self, origin: &str) -> bool {
+        self.keys.read().unwrap()
+            .values()
+            .any(|k| k.enabled && k.origins.iter().any(|allowed| origin_matches(origin, allowed)))
+    }
+
+    pub fn print(&self) {
+        println!("{:<36} {:<8} {:<10} {:<9} {:<20} NAME", "KEY", "ENABLED", "CHAR LIMIT", "REQ/MIN", "ORIGINS");
+        for key in self.keys.read().unwrap().values() {
+            println!("{:<36} {:<8} {:<10} {:<9} {:<20} {}",
+                key.key,
+                if key.enabled { "yes" } else { "no" },
+                key.char_limit.map_or("-".to_string(), |l| l.to_string()),
+                key.req_limit.map_or("-".to_string(), |l| l.to_string()),
+                if key.origins.is_empty() { "*".to_string() } else { key.origins.join(",") },
+                key.name
+            );
+        }
+    }
+}
+
+/// Whether the Origin header of a request matches `allowed`,
+/// which can be a full origin (`https://example.com`) or a host name
+pub fn origin_matches(origin: &str, allowed: &str) -> bool {
+    let host = origin.split_once("://").map_or(origin, |(_, host)| host);
+    origin == allowed || host == allowed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_full_origin() {
+        assert!(origin_matches("https://example.com", "https://example.com"));
+        assert!(!origin_matches("http://example.com", "https://example.com"));
+        assert!(!origin_matches("https://example.com:8443", "https://example.com"));
+    }
+
+    #[test]
+    fn matches_host_name() {
+        assert!(origin_matches("https://example.com", "example.com"));
+        assert!(origin_matches("http://example.com", "example.com"));
+        assert!(origin_matches("http://localhost:8080", "localhost:8080"));
+        assert!(!origin_matches("http://localhost:8080", "localhost"));
+    }
+
+    #[test]
+    fn rejects_similar_hosts() {
+        assert!(!origin_matches("https://evil-example.com", "example.com"));
+        assert!(!origin_matches("https://example.com.evil.org", "example.com"));
+        assert!(!origin_matches("https://sub.example.com", "example.com"));
+        assert!(!origin_matches("null", "example.com"));
+    }
+}
@@ -0,0 +1,255 @@
+use std::collections::HashSet;
+use std::path::Path;
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{ArgAction, ArgMatches, Command};
+use clap::parser::ValueSource;
+
+/// Prefix of the environment variables mirroring the command line options
+/// (e.g. `--char-limit` can be set with `LT_CHAR_LIMIT`)
+pub const ENV_PREFIX: &str = "LT_";
+
+/// Options that are never printed in clear
+const SECRETS: [&str; 2] = ["api_key", "admin_key"];
+
+/// Options that cannot be set from a configuration file
+const NOT_CONFIGURABLE: [&str; 3] = ["config", "help", "version"];
+
+/// Command line parser where every option can also be set with an `LT_*` environment
+/// variable or in the TOML configuration file. The precedence order is:
+/// command line > environment > configuration file > defaults.
+/// Also returns the ids of the options set by the configuration file.
+pub fn command(cmd: Command) -> Result<(Command, HashSet<String>)> {
+    with_config(cmd, config_path(std::env::args().skip(1)))
+}
+
+fn with_config(cmd: Command, path: Option<String>) -> Result<(Command, HashSet<String>)> {
+    let mut cmd = with_env(cmd);
+    let mut from_file = HashSet::new();
+
+    let Some(path) = path else {
+        return Ok((cmd, from_file));
+    };
+
+    let data = std::fs::read_to_string(&path)
+        .with_context(|| format!("Unable to read config file: {}", path))?;
+    let table: toml::Table = toml::from_str(&data)
+        .with_context(|| format!("Invalid config file: {}", path))?;
+
+    for (key, value) in table {
+        let id = cmd.get_arguments()
+            .find(|arg| !NOT_CONFIGURABLE.contains(&arg.get_id().as_str()) && option_names(arg).any(|name| name == key))
+            .map(|arg| arg.get_id().to_string())
+            .ok_or_else(|| anyhow!("Unknown option in config file {}: {}", path, key))?;
+        let values = to_values(&value).with_context(|| format!("Invalid value for {} in config file {}", key, path))?;
+
+        cmd = cmd.mut_arg(&id, |arg| arg.default_values(values));
+        from_file.insert(id);
+    }
+
+    Ok((cmd, from_file))
+}
+
+fn with_env(cmd: Command) -> Command {
+    let ids: Vec<(String, String)> = cmd.get_arguments()
+        .filter(|arg| !matches!(arg.get_action(), ArgAction::Help | ArgAction::Version))
+        .filter_map(|arg| arg.get_long().map(|long| (arg.get_id().to_string(), env_name(long))))
+        .collect();
+
+    ids.into_iter().fold(cmd, |cmd, (id, env)| cmd.mut_arg(id, |arg| arg.env(env)))
+}
+
+fn env_name(long: &str) -> String {
+    format!("{}{}", ENV_PREFIX, long.replace('-', "_").to_uppercase())
+}
+
+/// Names an option can have in the configuration file: its field name
+/// and its long flag, with dashes or underscores
+fn option_names(arg: &clap::Arg) -> impl Iterator<Item = String> {
+    let long = arg.get_long().map(|long| long.to_string());
+    [Some(arg.get_id().to_string()), long.clone(), long.map(|l| l.replace('-', "_"))]
+        .into_iter()
+        .flatten()
+}
+
+/// Path given with `--config` or `LT_CONFIG`, read before the rest
+/// of the command line since it provides the defaults of the other options
+fn config_path(mut args: impl Iterator<Item = String>) -> Option<String> {
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        }
+        if arg == "--config" {
+            return args.next();
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(path.to_string());
+        }
+    }
+    std::env::var(env_name("config")).ok().filter(|p| !p.is_empty())
+}
+
+fn to_values(value: &toml::Value) -> Result<Vec<String>> {
+    match value {
+        toml::Value::String(s) => Ok(vec![s.clone()]),
+        toml::Value::Integer(i) => Ok(vec![i.to_string()]),
+        toml::Value::Float(f) => Ok(vec![f.to_string()]),
+        toml::Value::Boolean(b) => Ok(vec![b.to_string()]),
+        toml::Value::Array(values) => values.iter()
+            .map(|v| match v {
+                toml::Value::Array(_) | toml::Value::Table(_) => bail!("nested arrays and tables are not supported"),
+                v => to_values(v).map(|mut values| values.remove(0)),
+            })
+            .collect(),
+        toml::Value::Table(_) | toml::Value::Datetime(_) => bail!("expected a string, number, boolean or array"),
+    }
+}
+
+/// Prints the effective configuration as TOML, with the source of each value
+/// and secrets redacted
+pub fn print(cmd: &Command, matches: &ArgMatches, from_file: &HashSet<String>) {
+    for line in lines(cmd, matches, from_file) {
+        println!("{}", line);
+    }
+}
+
+fn lines(cmd: &Command, matches: &ArgMatches, from_file: &HashSet<String>) -> Vec<String> {
+    let mut lines = Vec::new();
+    for arg in cmd.get_arguments() {
+        let id = arg.get_id().as_str();
+        if NOT_CONFIGURABLE.contains(&id) {
+            continue;
+        }
+
+        let source = match matches.value_source(id) {
+            Some(ValueSource::CommandLine) => "command line",
+            Some(ValueSource::EnvVariable) => "environment",
+            Some(ValueSource::DefaultValue) if from_file.contains(id) => "config file",
+            _ => "default",
+        };
+
+        let values: Vec<String> = matches.get_raw(id)
+            .map(|values| values.map(|v| v.to_string_lossy().to_string()).collect())
+            .unwrap_or_default();
+
+        let value = if SECRETS.contains(&id) && values.iter().any(|v| !v.is_empty()) {
+            "\"********\"".to_string()
+        } else if is_list(arg) {
+            format!("[{}]", values.iter().map(|v| toml_value(v)).collect::<Vec<_>>().join(", "))
+        } else {
+            values.first().map_or(String::new(), |v| toml_value(v))
+        };
+
+        let name = arg.get_long().map_or(id.to_string(), |long| long.replace('-', "_"));
+        if values.is_empty() && !is_list(arg) {
+            lines.push(format!("# {} = (not set)  # {}", name, source));
+        } else {
+            lines.push(format!("{} = {}  # {}", name, value, source));
+        }
+    }
+    lines
+}
+
+fn is_list(arg: &clap::Arg) -> bool {
+    matches!(arg.get_action(), ArgAction::Append)
+}
+
+fn toml_value(value: &str) -> String {
+    if value.parse::<i64>().is_ok() || value.parse::<f64>().is_ok_and(|f| f.is_finite()) || value == "true" || value == "false" {
+        value.to_string()
+    } else {
+        toml::Value::String(value.to_string()).to_string()
+    }
+}
+
+/// Fails if `path` is set but does not exist
+pub fn validate_path(option: &str, path: &str) -> Result<()> {
+    if !path.is_empty() && !Path::new(path).exists() {
+        bail!("--{} {}: file not found", option, path);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{Arg, CommandFactory};
+
+    /// Config file removed at the end of the test
+    struct ConfigFile(String);
+
+    impl ConfigFile {
+        fn new(data: &str) -> ConfigFile {
+            let path = std::env::temp_dir().join(format!("ltengine-config-{}.toml", uuid::Uuid::new_v4()));
+            std::fs::write(&path, data).unwrap();
+            ConfigFile(path.to_string_lossy().to_string())
+        }
+    }
+
+    impl Drop for ConfigFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn args(args: &[&str]) -> impl Iterator<Item = String> {
+        args.iter().map(|arg| arg.to_string())
+    }
+
+    #[test]
+    fn finds_config_path() {
+        assert_eq!(config_path(args(&["-p", "5000", "--config", "a.toml"])), Some("a.toml".to_string()));
+        assert_eq!(config_path(args(&["--config=b.toml"])), Some("b.toml".to_string()));
+        assert_eq!(config_path(args(&["--", "--config", "c.toml"])), std::env::var("LT_CONFIG").ok().filter(|p| !p.is_empty()));
+    }
+
+    #[test]
+    fn applies_precedence() {
+        let option = |name: &'static str| Arg::new(name).long(format!("precedence-test-{}", name)).default_value("default");
+        let cmd = Command::new("ltengine")
+            .arg(option("cli"))
+            .arg(option("env"))
+            .arg(option("file"))
+            .arg(option("unset"));
+        let config = ConfigFile::new("cli = \"file\"\nenv = \"file\"\nprecedence-test-file = \"file\"\n");
+        // SAFETY: the variables are only read by this test, and std serializes its own
+        // accesses to the environment
+        unsafe {
+            std::env::set_var("LT_PRECEDENCE_TEST_CLI", "env");
+            std::env::set_var("LT_PRECEDENCE_TEST_ENV", "env");
+        }
+
+        let (cmd, from_file) = with_config(cmd, Some(config.0.clone())).unwrap();
+        let matches = cmd.clone().get_matches_from(["ltengine", "--precedence-test-cli", "cli"]);
+        let value = |id| matches.get_one::<String>(id).unwrap().as_str();
+        assert_eq!([value("cli"), value("env"), value("file"), value("unset")], ["cli", "env", "file", "default"]);
+
+        let mut lines = lines(&cmd, &matches, &from_file);
+        lines.sort();
+        assert_eq!(lines, [
+            "precedence_test_cli = \"cli\"  # command line",
+            "precedence_test_env = \"env\"  # environment",
+            "precedence_test_file = \"file\"  # config file",
+            "precedence_test_unset = \"default\"  # default",
+        ]);
+    }
+
+    #[test]
+    fn rejects_unknown_options() {
+        let config = ConfigFile::new("char_limt = 100\n");
+        let err = with_config(crate::Args::command(), Some(config.0.clone())).unwrap_err();
+        assert!(err.to_string().starts_with("Unknown option in config file"), "{}", err);
+    }
+
+    #[test]
+    fn redacts_secrets() {
+        let config = ConfigFile::new("admin_key = \"file-secret\"\nchar_limit = 100\n");
+        let (cmd, from_file) = with_config(crate::Args::command(), Some(config.0.clone())).unwrap();
+        let matches = cmd.clone().get_matches_from(["ltengine", "--api-key", "cli-secret"]);
+        let lines = lines(&cmd, &matches, &from_file);
+
+        assert!(lines.iter().all(|line| !line.contains("secret")), "{:?}", lines);
+        assert!(lines.contains(&"api_key = \"********\"  # command line".to_string()), "{:?}", lines);
+        assert!(lines.contains(&"admin_key = \"********\"  # config file".to_string()), "{:?}", lines);
+        assert!(lines.contains(&"char_limit = 100  # config file".to_string()), "{:?}", lines);
+    }
+}
@@ -0,0 +1,79 @@
+use std::str::FromStr;
+use std::sync::Arc;
+use actix_cors::Cors;
+use actix_web::http::Method;
+use actix_web::http::header::HeaderName;
+use crate::api_keys::{origin_matches, ApiKeyStore};
+
+/// Response headers that browsers are allowed to read
+const EXPOSED_HEADERS: [&str; 4] = ["retry-after", "x-queue-position", "x-queue-eta", "x-request-id"];
+
+/// Cross-origin settings. By default any origin can call the API, like LibreTranslate.
+#[derive(clap::Args, Clone, Debug)]
+pub struct CorsOptions {
+    /// Origins allowed to make cross-origin requests, e.g. `https://example.com` or `example.com` (comma separated, default: any)
+    #[arg(long = "cors-origin", value_delimiter = ',')]
+    pub cors_origins: Vec<String>,
+
+    /// Also allow the origins configured on API keys (see --api-keys-file)
+    #[arg(long)]
+    pub cors_key_origins: bool,
+
+    /// HTTP methods allowed in cross-origin requests (comma separated, default: any)
+    #[arg(long = "cors-method", value_delimiter = ',')]
+    pub cors_methods: Vec<String>,
+
+    /// Request headers allowed in cross-origin requests (comma separated, default: any)
+    #[arg(long = "cors-header", value_delimiter = ',')]
+    pub cors_headers: Vec<String>,
+
+    /// Seconds browsers can cache the result of preflight requests
+    #[arg(long)]
+    pub cors_max_age: Option<usize>,
+}
+
+impl CorsOptions {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(method) = self.cors_methods.iter().find(|m| Method::from_str(m).is_err()) {
+            return Err(format!("--cors-method {} is not a valid HTTP method", method));
+        }
+        if let Some(header) = self.cors_headers.iter().find(|h| HeaderName::from_str(h).is_err()) {
+            return Err(format!("--cors-header {} is not a valid header name", header));
+        }
+        Ok(())
+    }
+
+    /// CORS middleware of one worker. Origins of API keys are checked on every
+    /// request, so that keys created at runtime are taken into account.
+    pub fn middleware(&self, keys: Option<Arc<ApiKeyStore>>) -> Cors {
+        let mut cors = Cors::default().expose_headers(EXPOSED_HEADERS);
+
+        cors = if self.cors_origins.is_empty() && !self.cors_key_origins {
+            cors.allow_any_origin().send_wildcard()
+        } else {
+            let origins = self.cors_origins.clone();
+            let key_origins = self.cors_key_origins;
+            cors.allowed_origin_fn(move |origin, _| {
+                let Ok(origin) = origin.to_str() else {
+                    return false;
+                };
+                origins.iter().any(|allowed| origin_matches(origin, allowed))
+                    || (key_origins && keys.as_ref().is_some_and(|keys| keys.origin_allowed(origin)))
+            })
+        };
+
+        cors = if self.cors_methods.is_empty() {
+            cors.allow_any_method()
+        } else {
+            cors.allowed_methods(self.cors_methods.iter().map(String::as_str))
+        };
+
+        cors = if self.cors_headers.is_empty() {
+            cors.allow_any_header()
+        } else {
+            cors.allowed_headers(self.cors_headers.iter().map(String::as_str))
+        };
+
+        cors.max_age(self.cors_max_age)
+    }
+}
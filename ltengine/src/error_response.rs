@@ -1,13 +1,56 @@
 use serde::Serialize;
+use utoipa::ToSchema;
 use std::fmt::{Display, Formatter, Result as FmtResult};
 use serde_json::to_string_pretty;
 use actix_web::{ResponseError, HttpResponse, body::BoxBody};
-use actix_web::http::StatusCode;
+use actix_web::http::{header, StatusCode};
 
-#[derive(Debug, Serialize)]
+/// Stable identifier of an error, for clients that should not parse `error` messages
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// Malformed request or invalid parameter
+    InvalidRequest,
+    /// A required parameter is missing or empty
+    MissingParameter,
+    /// The language is not supported by the server or the model
+    UnsupportedLanguage,
+    /// The format is neither `text` nor `html`
+    UnsupportedFormat,
+    /// The text is longer than the character limit
+    TextTooLong,
+    /// The API key is missing, unknown, disabled or not allowed from this origin
+    InvalidApiKey,
+    /// The admin key header is missing or does not match --admin-key
+    InvalidAdminKey,
+    /// The client sent too many requests or characters, see Retry-After
+    RateLimited,
+    /// Too many requests are waiting for the model, see Retry-After
+    ModelBusy,
+    /// The requested model (or the default one) is not loaded
+    ModelNotLoaded,
+    /// The model failed to produce a translation
+    InferenceFailed,
+    /// The server is shutting down, retry on another instance or after Retry-After
+    ShuttingDown,
+    /// The feature is disabled on this server
+    FeatureDisabled,
+    /// The endpoint is not supported yet
+    NotImplemented,
+    /// Unexpected server error, e.g. a file could not be written
+    InternalError,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
 pub struct ErrorResponse {
+  /// Human readable message (LibreTranslate compatible)
   pub error: String,
-  pub status: u16
+  #[serde(skip)]
+  pub status: u16,
+  pub code: ErrorCode,
+  /// Seconds after which the client can retry (sent as Retry-After)
+  #[serde(skip)]
+  pub retry_after: Option<u64>
 }
 
 impl Display for ErrorResponse {
@@ -22,15 +65,22 @@ impl ResponseError for ErrorResponse {
     }
 
     fn error_response(&self) -> HttpResponse<BoxBody> {
-        HttpResponse::build(self.status_code()).json(serde_json::json!({"error": self.error.clone()}))
+        let mut res = HttpResponse::build(self.status_code());
+        if let Some(seconds) = self.retry_after {
+            res.insert_header((header::RETRY_AFTER, seconds));
+        }
+        res.json(self)
     }
 }
 
 impl From<actix_web::Error> for ErrorResponse {
     fn from(err: actix_web::Error) -> Self {
+        let status = err.as_response_error().status_code();
         ErrorResponse {
             error: err.to_string(),
-            status: err.as_response_error().status_code().as_u16(),
+            status: status.as_u16(),
+            code: if status.is_server_error() { ErrorCode::InternalError } else { ErrorCode::InvalidRequest },
+            retry_after: None,
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,93 @@
+use std::sync::RwLock;
+use std::time::Duration;
+use serde::Serialize;
+use utoipa::ToSchema;
+use crate::metrics::METRICS;
+
+#[derive(Clone, Debug, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case", tag = "status", content = "error")]
+pub enum ServerStatus {
+    /// Models are being downloaded and loaded
+    Loading,
+    /// Running a test translation
+    WarmingUp,
+    Ready,
+    /// New translations are refused while the queued ones complete
+    ShuttingDown,
+    /// Loading or the warm-up translation failed
+    Failed(String),
+}
+
+/// Reported by /health and /ready instead of the status when translations cannot be served in time
+#[derive(Clone, Debug, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case", tag = "status")]
+pub enum Degraded {
+    /// The running job has held a model for longer than --stall-timeout
+    Stalled {
+        /// Seconds since the job started
+        running_for: u64
+    },
+    /// More requests are waiting than --ready-queue-limit
+    Saturated {
+        /// Number of waiting requests
+        queue: i64
+    },
+}
+
+/// Body of the /health and /ready responses that report a problem
+#[derive(Clone, Debug, Serialize, ToSchema)]
+#[serde(untagged)]
+pub enum Unhealthy {
+    Status(ServerStatus),
+    Degraded(Degraded),
+}
+
+/// Tracks whether the server can take translation requests
+pub struct Health {
+    status: RwLock<ServerStatus>,
+    /// Number of requests waiting for the LLM above which we report not ready
+    queue_limit: i64,
+    /// Time a job can hold the LLM before we report the inference as stuck
+    stall_timeout: Option<Duration>,
+}
+
+impl Health {
+    pub fn new(queue_limit: usize, stall_timeout: Option<Duration>) -> Health {
+        Health {
+            status: RwLock::new(ServerStatus::Loading),
+            queue_limit: queue_limit as i64,
+            stall_timeout,
+        }
+    }
+
+    pub fn status(&self) -> ServerStatus {
+        self.status.read().unwrap().clone()
+    }
+
+    pub fn set_status(&self, status: ServerStatus) {
+        let mut current = self.status.write().unwrap();
+        // Shutting down is final, even if loading completes in the meantime
+        if *current != ServerStatus::ShuttingDown {
+            *current = status;
+        }
+    }
+
+    /// The process can still do useful work (it is not stuck after a failure)
+    pub fn is_alive(&self) -> bool {
+        !matches!(self.status(), ServerStatus::Failed(_))
+    }
+
+    /// Models are loaded, warmed up and the queue is not saturated
+    pub fn is_ready(&self) -> bool {
+        self.status() == ServerStatus::Ready && !self.is_saturated()
+    }
+
+    pub fn is_saturated(&self) -> bool {
+        METRICS.queue_depth() > self.queue_limit
+    }
+
+    /// The running job (`running_for`) has held the LLM for too long, e.g. the inference is stuck
+    pub fn is_stalled(&self, running_for: Option<Duration>) -> bool {
+        matches!((self.stall_timeout, running_for), (Some(timeout), Some(running)) if running > timeout)
+    }
+}
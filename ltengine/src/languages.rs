@@ -1,7 +1,10 @@
 use serde::Serialize;
+use utoipa::ToSchema;
 use once_cell::sync::Lazy;
 use whatlang::{Lang, Detector};
 use std::collections::HashMap;
+use anyhow::{anyhow, bail};
+use crate::lexilang;
 
 const LANGS: &[(&str, &str, &str)] = &[
     ("en", "", "English"),
@@ -55,11 +58,11 @@ const LANGS: &[(&str, &str, &str)] = &[
     ("vi", "", "Vietnamese"),
 ];
 
-#[derive(Serialize)]
+#[derive(Serialize, Clone, ToSchema)]
 pub struct Language {
     pub code: &'static str,
     pub name: &'static str,
-    pub targets: &'static [&'static str],
+    pub targets: Vec<&'static str>,
 
     #[serde(skip)]
     pub lang_detect: Option<&'static Lang>,
@@ -92,7 +95,7 @@ pub static LANGUAGES: Lazy<Vec<Language>> = Lazy::new(|| {
             Language {
                 code: if alias != "" { alias } else { code },
                 name,
-                targets: Box::leak(targets.into_boxed_slice()),
+                targets,
                 lang_detect: lang_detect_map.get(name).map(|v| &**v),
                 internal_code: code
             }
@@ -114,33 +117,169 @@ pub fn get_language_from_code(code: &String) -> Option<&'static Language>{
     LANGUAGES_MAP.get(internal_code).map(|v| &**v)
 }
 
+/// Languages offered by a model
+pub struct LanguageSet {
+    languages: Vec<Language>
+}
+
+impl LanguageSet {
+    /// Restricts the languages to `codes` (internal codes), or all languages if `None`
+    pub fn new(codes: Option<&[String]>) -> LanguageSet {
+        let Some(codes) = codes else {
+            return LanguageSet { languages: LANGUAGES.clone() };
+        };
+
+        let supported: Vec<&Language> = LANGUAGES.iter()
+            .filter(|l| codes.iter().any(|c| c == l.internal_code))
+            .collect();
+        let targets: Vec<&'static str> = supported.iter().map(|l| l.code).collect();
+
+        LanguageSet {
+            languages: supported.into_iter()
+                .map(|l| Language { targets: targets.clone(), ..l.clone() })
+                .collect()
+        }
+    }
+
+    pub fn all(&self) -> &[Language] {
+        &self.languages
+    }
+
+    pub fn supports(&self, lang: &Language) -> bool {
+        self.languages.iter().any(|l| l.internal_code == lang.internal_code)
+    }
+}
+
+/// Rough number of output tokens per input token when translating into `lang`.
+/// Scripts that are poorly covered by the tokenizer need more tokens for the same content.
+pub fn output_token_ratio(lang: &Language) -> f32 {
+    match lang.internal_code {
+        "ar" | "bn" | "el" | "fa" | "he" | "hi" | "ja" | "ko" | "th" | "ur" | "zh" | "zt" => 3.0,
+        "bg" | "ru" | "sr" | "uk" => 2.5,
+        _ => 2.0
+    }
+}
+
 pub struct LangDetect{
     pub language: &'static Language,
     pub confidence: i32
 }
 
-pub fn detect_lang(q: &String) -> LangDetect {
+/// Detects the language of `q` with whatlang, also returning whether the result is reliable
+fn detect_whatlang(q: &str) -> Option<(LangDetect, bool)> {
     let allowlist: Vec<Lang> = LANGUAGES
         .iter()
         .filter_map(|l| l.lang_detect.copied())
         .collect();
 
     let detector = Detector::with_allowlist(allowlist);
-    if let Some(info) = detector.detect(q) {
-        let lang = info.lang();
-        let confidence = info.confidence();
-
-        LANGUAGES.iter()
-            .find(|l| l.lang_detect == Some(&lang))
-            .map(|l| LangDetect { language: l, confidence: (confidence * 100.0) as i32 })
-            .unwrap_or(LangDetect {
-                language: &LANGUAGES[0],
-                confidence: 0,
-            })
-    } else {
-        LangDetect {
+    let info = detector.detect(q)?;
+    let lang = info.lang();
+
+    LANGUAGES.iter()
+        .find(|l| l.lang_detect == Some(&lang))
+        .map(|l| (LangDetect { language: l, confidence: (info.confidence() * 100.0) as i32 }, info.is_reliable()))
+}
+
+/// Detects the language of `q` with the LexiLang dictionaries
+fn detect_lexilang(q: &str) -> Option<LangDetect> {
+    let candidates: Vec<&str> = LANGUAGES.iter().map(|l| l.internal_code).collect();
+    let detected = lexilang::detect(q, &candidates)?;
+    LANGUAGES_MAP.get(detected.code)
+        .map(|l| LangDetect { language: l, confidence: (detected.confidence * 100.0) as i32 })
+}
+
+/// Detects the language of `q`. Short texts and texts whatlang is unsure about are matched
+/// against the LexiLang dictionaries first, which also tell traditional Chinese apart.
+pub fn detect_lang(q: &String) -> LangDetect {
+    let whatlang = detect_whatlang(q);
+    let use_lexilang = q.chars().count() <= lexilang::SHORT_TEXT_CHARS || match &whatlang {
+        Some((detected, reliable)) => !reliable || detected.language.internal_code == "zh",
+        None => true
+    };
+
+    use_lexilang.then(|| detect_lexilang(q)).flatten()
+        .or(whatlang.map(|(detected, _)| detected))
+        .unwrap_or(LangDetect {
             language: &LANGUAGES[0],
             confidence: 0,
+        })
+}
+
+/// Prints the accuracy of language detection on `test_set` (`<internal code>\t<text>` lines),
+/// with whatlang alone and combined with LexiLang
+pub fn print_detection_benchmark(test_set: &str) -> anyhow::Result<()> {
+    // (code, samples, detected by whatlang, detected by both)
+    let mut results: Vec<(&str, usize, usize, usize)> = Vec::new();
+    let mut misses = Vec::new();
+
+    for (i, line) in test_set.lines().enumerate().filter(|(_, l)| !l.trim().is_empty() && !l.starts_with('#')) {
+        let (code, text) = line.split_once('\t')
+            .ok_or_else(|| anyhow!("Invalid test set (line {}): expected <code><TAB><text>", i + 1))?;
+        if !LANGUAGES_MAP.contains_key(code) {
+            bail!("Invalid test set (line {}): unknown language {}", i + 1, code);
+        }
+
+        let whatlang = detect_whatlang(text).map(|(d, _)| d.language.internal_code);
+        let combined = detect_lang(&text.to_string()).language.internal_code;
+        if combined != code {
+            misses.push(format!("{} -> {}: {}", code, combined, text));
+        }
+
+        let index = match results.iter().position(|(c, ..)| *c == code) {
+            Some(index) => index,
+            None => {
+                results.push((code, 0, 0, 0));
+                results.len() - 1
+            }
+        };
+        let entry = &mut results[index];
+        entry.1 += 1;
+        entry.2 += (whatlang == Some(code)) as usize;
+        entry.3 += (combined == code) as usize;
+    }
+
+    let percent = |n: usize, total: usize| format!("{:.0}%", n as f32 * 100.0 / total.max(1) as f32);
+    println!("{:<6} {:>7} {:>9} {:>9}", "LANG", "SAMPLES", "WHATLANG", "COMBINED");
+    for (code, total, whatlang, combined) in &results {
+        println!("{:<6} {:>7} {:>9} {:>9}", code, total, percent(*whatlang, *total), percent(*combined, *total));
+    }
+    let total: usize = results.iter().map(|r| r.1).sum();
+    let whatlang: usize = results.iter().map(|r| r.2).sum();
+    let combined: usize = results.iter().map(|r| r.3).sum();
+    println!("{:<6} {:>7} {:>9} {:>9}", "total", total, percent(whatlang, total), percent(combined, total));
+
+    if !misses.is_empty() {
+        println!("\nMisdetected:");
+        for miss in misses {
+            println!("  {}", miss);
         }
     }
-}
\ No newline at end of file
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_benchmark_texts() {
+        let samples: Vec<(&str, &str)> = include_str!("../lexilang/benchmark.tsv")
+            .lines()
+            .filter(|l| !l.trim().is_empty() && !l.starts_with('#'))
+            .map(|l| l.split_once('\t').unwrap())
+            .collect();
+        let correct = samples.iter()
+            .filter(|(code, text)| detect_lang(&text.to_string()).language.internal_code == *code)
+            .count();
+
+        let accuracy = correct as f64 / samples.len() as f64;
+        assert!(accuracy >= 0.85, "accuracy {:.2} on {} texts", accuracy, samples.len());
+    }
+
+    #[test]
+    fn detects_long_texts_with_whatlang() {
+        let text = "La détection de la langue des textes longs repose sur whatlang, qui est fiable lorsque le texte contient plusieurs phrases.".to_string();
+        assert_eq!(detect_lang(&text).language.internal_code, "fr");
+    }
+}
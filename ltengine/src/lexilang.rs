@@ -0,0 +1,256 @@
+use std::collections::HashMap;
+use once_cell::sync::Lazy;
+
+/// Texts up to this many characters are detected with the dictionaries rather than whatlang,
+/// which needs more context to be reliable
+pub const SHORT_TEXT_CHARS: usize = 50;
+
+/// Words and letters of each language, ported from LexiLang
+/// (https://github.com/LibreTranslate/LexiLang). The letters are the non-ASCII ones
+/// used by the language, words made of other letters are evidence against it.
+/// Sorted by number of speakers, which breaks ties.
+const DICTIONARIES: &[(&str, &str, &str)] = &[
+    ("en", "", include_str!("../lexilang/en.txt")),
+    ("es", "áéíóúñü", include_str!("../lexilang/es.txt")),
+    ("ar", "ءآأؤإئابةتثجحخدذرزسشصضطظعغفقكلمنهوىيًٌٍَُِّْ", include_str!("../lexilang/ar.txt")),
+    ("pt", "áâãàçéêíóôõú", include_str!("../lexilang/pt.txt")),
+    ("pb", "áâãàçéêíóôõú", include_str!("../lexilang/pb.txt")),
+    ("ru", "абвгдеёжзийклмнопрстуфхцчшщъыьэюя", include_str!("../lexilang/ru.txt")),
+    ("fr", "àâæçéèêëîïôœùûüÿ", include_str!("../lexilang/fr.txt")),
+    ("de", "äöüß", include_str!("../lexilang/de.txt")),
+    ("id", "", include_str!("../lexilang/id.txt")),
+    ("ur", "ءآأؤئابپتٹثجچحخدڈذرڑزژسشصضطظعغفقکگلمنںوهہھیےۓًٌٍَُِّْ", include_str!("../lexilang/ur.txt")),
+    ("tr", "çğıöşüâîû", include_str!("../lexilang/tr.txt")),
+    ("vi", "àáảãạăằắẳẵặâầấẩẫậèéẻẽẹêềếểễệìíỉĩịòóỏõọôồốổỗộơờớởỡợùúủũụưừứửữựỳýỷỹỵđ", include_str!("../lexilang/vi.txt")),
+    ("it", "àèéìíîòóùú", include_str!("../lexilang/it.txt")),
+    ("fa", "ءآأؤئابپتثجچحخدذرزژسشصضطظعغفقکگلمنوهیۀًٌٍَُِّْ", include_str!("../lexilang/fa.txt")),
+    ("pl", "ąćęłńóśźż", include_str!("../lexilang/pl.txt")),
+    ("uk", "абвгґдеєжзиіїйклмнопрстуфхцчшщьюя", include_str!("../lexilang/uk.txt")),
+    ("tl", "ñ", include_str!("../lexilang/tl.txt")),
+    ("nl", "áéëèïóöüú", include_str!("../lexilang/nl.txt")),
+    ("ro", "ăâîșțşţ", include_str!("../lexilang/ro.txt")),
+    ("ms", "", include_str!("../lexilang/ms.txt")),
+    ("hu", "áéíóöőúüű", include_str!("../lexilang/hu.txt")),
+    ("cs", "áčďéěíňóřšťúůýž", include_str!("../lexilang/cs.txt")),
+    ("sv", "åäöé", include_str!("../lexilang/sv.txt")),
+    ("sr", "абвгдђежзијклљмнњопрстћуфхцчџшčćđšž", include_str!("../lexilang/sr.txt")),
+    ("bg", "абвгдежзийклмнопрстуфхцчшщъьюяѝ", include_str!("../lexilang/bg.txt")),
+    ("az", "çəğıöşü", include_str!("../lexilang/az.txt")),
+    ("ca", "àçèéíïòóúü·", include_str!("../lexilang/ca.txt")),
+    ("sk", "áäčďéíĺľňóôŕšťúýž", include_str!("../lexilang/sk.txt")),
+    ("da", "æøåé", include_str!("../lexilang/da.txt")),
+    ("fi", "äöåšž", include_str!("../lexilang/fi.txt")),
+    ("nb", "æøåéèêóòô", include_str!("../lexilang/nb.txt")),
+    ("lt", "ąčęėįšųūž", include_str!("../lexilang/lt.txt")),
+    ("sl", "čšž", include_str!("../lexilang/sl.txt")),
+    ("lv", "āčēģīķļņšūž", include_str!("../lexilang/lv.txt")),
+    ("et", "äöõüšž", include_str!("../lexilang/et.txt")),
+    ("gl", "áéíóúñü", include_str!("../lexilang/gl.txt")),
+    ("sq", "çë", include_str!("../lexilang/sq.txt")),
+    ("eu", "ñ", include_str!("../lexilang/eu.txt")),
+    ("ga", "áéíóú", include_str!("../lexilang/ga.txt")),
+    ("eo", "ĉĝĥĵŝŭ", include_str!("../lexilang/eo.txt")),
+];
+
+/// Characters only found in simplified Chinese
+const SIMPLIFIED: &str = "谢吗湾们个这说来时会对国学开关还点发问过后见长从现东车书语话电门马体鸟鱼风飞爱实应动无么样头经当将与号万间认让请读写买卖钱听题难医欢气业类汉华岁亲旧边远进运选错钟阳队双云颜显余馆员网络级饭条记热较";
+/// Characters only found in traditional Chinese
+const TRADITIONAL: &str = "謝嗎灣們個這說來時會對國學開關還點發問過後見長從現東車書語話電門馬體鳥魚風飛愛實應動無麼樣頭經當將與號萬間認讓請讀寫買賣錢聽題難醫歡氣業類漢華歲親舊邊遠進運選錯鐘陽隊雙雲顏顯餘館員網絡級飯條記熱較";
+
+/// Word evidence is worth this much more than letter evidence
+const LETTER_WEIGHT: f32 = 0.5;
+
+pub struct Detection {
+    /// Internal language code
+    pub code: &'static str,
+    /// Between 0 and 1
+    pub confidence: f32,
+}
+
+struct Dictionary {
+    code: &'static str,
+    letters: &'static str,
+}
+
+/// Languages of each word
+static INDEX: Lazy<HashMap<&'static str, Vec<usize>>> = Lazy::new(|| {
+    let mut index: HashMap<&'static str, Vec<usize>> = HashMap::new();
+    for (i, (_, _, words)) in DICTIONARIES.iter().enumerate() {
+        for word in words.lines().map(str::trim).filter(|w| !w.is_empty() && !w.starts_with('#')) {
+            let langs = index.entry(word).or_default();
+            if !langs.contains(&i) {
+                langs.push(i);
+            }
+        }
+    }
+    index
+});
+
+static LANGS: Lazy<Vec<Dictionary>> = Lazy::new(|| {
+    DICTIONARIES.iter().map(|&(code, letters, _)| Dictionary { code, letters }).collect()
+});
+
+/// Languages that can be identified from their script alone
+fn script_language(c: char) -> Option<&'static str> {
+    match c {
+        '\u{0370}'..='\u{03FF}' | '\u{1F00}'..='\u{1FFF}' => Some("el"),
+        '\u{0590}'..='\u{05FF}' => Some("he"),
+        '\u{0900}'..='\u{097F}' => Some("hi"),
+        '\u{0980}'..='\u{09FF}' => Some("bn"),
+        '\u{0E00}'..='\u{0E7F}' => Some("th"),
+        '\u{1100}'..='\u{11FF}' | '\u{3130}'..='\u{318F}' | '\u{AC00}'..='\u{D7AF}' => Some("ko"),
+        '\u{3040}'..='\u{30FF}' | '\u{31F0}'..='\u{31FF}' | '\u{FF66}'..='\u{FF9F}' => Some("ja"),
+        _ => None
+    }
+}
+
+fn is_han(c: char) -> bool {
+    matches!(c, '\u{4E00}'..='\u{9FFF}' | '\u{3400}'..='\u{4DBF}' | '\u{F900}'..='\u{FAFF}')
+}
+
+/// Lowercased words of `text`. Elisions such as `l'homme` are split into `l'` and `homme`
+/// unless the whole word is known (e.g. `don't`).
+fn words(text: &str) -> Vec<String> {
+    let text = text.to_lowercase().replace('’', "'");
+    let mut words = Vec::new();
+    for token in text.split(|c: char| !(c.is_alphabetic() || c == '\'' || c == '·' || ('\u{064B}'..='\u{0652}').contains(&c))) {
+        let token = token.trim_matches(|c| c == '\'' || c == '·');
+        if token.is_empty() {
+            continue;
+        }
+        match token.split_once('\'') {
+            Some((prefix, rest)) if !INDEX.contains_key(token) => {
+                words.push(format!("{}'", prefix));
+                words.push(rest.to_string());
+            },
+            _ => words.push(token.to_string())
+        }
+    }
+    words
+}
+
+/// Detects the language of `text` among `candidates` (internal codes), or `None`
+/// if there is no evidence for any of them
+pub fn detect(text: &str, candidates: &[&str]) -> Option<Detection> {
+    let letters: Vec<char> = text.chars().filter(|c| c.is_alphabetic()).collect();
+    if letters.is_empty() {
+        return None;
+    }
+    let total = letters.len() as f32;
+    let allowed = |code: &str| candidates.contains(&code);
+
+    // Kana only appear in Japanese, which also uses Han characters
+    let kana = letters.iter().filter(|c| script_language(**c) == Some("ja")).count();
+    let han = letters.iter().filter(|c| is_han(**c)).count();
+    if kana > 0 && allowed("ja") && (kana + han) as f32 >= total / 2.0 {
+        return Some(Detection { code: "ja", confidence: (kana + han) as f32 / total });
+    }
+    if han as f32 >= total / 2.0 {
+        let simplified = letters.iter().filter(|c| SIMPLIFIED.contains(**c)).count();
+        let traditional = letters.iter().filter(|c| TRADITIONAL.contains(**c)).count();
+        let code = if traditional > simplified && allowed("zt") { "zt" } else { "zh" };
+        if allowed(code) {
+            return Some(Detection { code, confidence: han as f32 / total });
+        }
+    }
+
+    let mut scripts: HashMap<&'static str, usize> = HashMap::new();
+    for code in letters.iter().filter_map(|c| script_language(*c)) {
+        *scripts.entry(code).or_insert(0) += 1;
+    }
+    let dominant = scripts.into_iter()
+        .max_by_key(|(_, count)| *count)
+        .filter(|(code, count)| *count as f32 >= total / 2.0 && allowed(code));
+    if let Some((code, count)) = dominant {
+        return Some(Detection { code, confidence: count as f32 / total });
+    }
+
+    let words = words(text);
+    if words.is_empty() {
+        return None;
+    }
+
+    let mut scores = vec![0.0f32; LANGS.len()];
+    for word in &words {
+        if let Some(langs) = INDEX.get(word.as_str()) {
+            // Words shared by several languages are weaker evidence
+            for &i in langs {
+                scores[i] += 1.0 / langs.len() as f32;
+            }
+            continue;
+        }
+
+        let special: Vec<char> = word.chars().filter(|c| !c.is_ascii() && *c != '\'').collect();
+        if special.is_empty() {
+            continue;
+        }
+        let compatible: Vec<usize> = LANGS.iter()
+            .enumerate()
+            .filter(|(_, lang)| special.iter().all(|c| lang.letters.contains(*c)))
+            .map(|(i, _)| i)
+            .collect();
+        for (i, score) in scores.iter_mut().enumerate() {
+            if compatible.contains(&i) {
+                *score += LETTER_WEIGHT / compatible.len() as f32;
+            } else {
+                *score -= 1.0;
+            }
+        }
+    }
+
+    // Ties go to the language with the most speakers
+    let (best, score) = LANGS.iter()
+        .zip(scores)
+        .filter(|(lang, _)| allowed(lang.code))
+        .fold(None, |best: Option<(&Dictionary, f32)>, (lang, score)| match best {
+            Some((_, best_score)) if best_score >= score => best,
+            _ => Some((lang, score))
+        })?;
+
+    (score > 0.0).then(|| Detection {
+        code: best.code,
+        confidence: (score / words.len() as f32).min(1.0),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: &[&str] = &["en", "fr", "it", "es", "de", "ja", "zh", "zt", "el"];
+
+    #[test]
+    fn splits_elisions() {
+        assert_eq!(words("L'homme qu’il voit"), ["l'", "homme", "qu'", "il", "voit"]);
+    }
+
+    #[test]
+    fn keeps_known_contractions() {
+        assert_eq!(words("I don't know"), ["i", "don't", "know"]);
+        assert_eq!(words("'quoted' words!"), ["quoted", "words"]);
+    }
+
+    #[test]
+    fn tells_chinese_scripts_apart() {
+        assert_eq!(detect("谢谢你们", ALL).unwrap().code, "zh");
+        assert_eq!(detect("謝謝你們", ALL).unwrap().code, "zt");
+        // Characters shared by both scripts
+        assert_eq!(detect("你好", ALL).unwrap().code, "zh");
+        // Without traditional Chinese among the candidates
+        assert_eq!(detect("謝謝你們", &["en", "zh"]).unwrap().code, "zh");
+    }
+
+    #[test]
+    fn detects_japanese_and_scripts() {
+        assert_eq!(detect("ありがとう", ALL).unwrap().code, "ja");
+        assert_eq!(detect("日本語を話します", ALL).unwrap().code, "ja");
+        assert_eq!(detect("Καλημέρα", ALL).unwrap().code, "el");
+    }
+
+    #[test]
+    fn detects_short_texts() {
+        assert_eq!(detect("Merci beaucoup", ALL).unwrap().code, "fr");
+        assert_eq!(detect("Where is the station?", ALL).unwrap().code, "en");
+        assert!(detect("1234 !?", ALL).is_none());
+    }
+}
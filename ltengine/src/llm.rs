@@ -1,22 +1,274 @@
 use llama_cpp_2::context::params::LlamaContextParams;
 use llama_cpp_2::llama_backend::LlamaBackend;
 use llama_cpp_2::model::params::LlamaModelParams;
-use llama_cpp_2::model::{LlamaModel, LlamaChatMessage};
+use llama_cpp_2::model::{LlamaModel, LlamaChatMessage, LlamaChatTemplate};
 use llama_cpp_2::token::LlamaToken;
 use llama_cpp_2::context::LlamaContext;
 use llama_cpp_2::model::{AddBos, Special};
 use llama_cpp_2::llama_batch::LlamaBatch;
-use llama_cpp_2::sampling::LlamaSampler;
-use llama_cpp_2::{send_logs_to_tracing, LogOptions};
 use std::num::NonZeroU32;
 use std::path::PathBuf;
-use std::sync::Mutex;
-use anyhow::{Result, Context};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, warn, Span};
+use anyhow::{anyhow, Result, Context};
+use crate::metrics::METRICS;
+use crate::queue::{InferenceQueue, Priority, QueueClosed, QueueFull, Ticket};
+use crate::sampling::SamplingConfig;
+use crate::prompt::{Prompt, PromptTemplate};
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct LLMOptions {
+    /// Use CPU only
+    #[arg(long)]
+    pub cpu: bool,
+
+    /// Disable reuse of the evaluated system prompt across requests
+    #[arg(long)]
+    pub no_prompt_cache: bool,
+
+    /// Memory used by the prompt cache in MiB. The least recently used
+    /// system prompts are evicted first
+    #[arg(long, default_value_t = 256)]
+    pub prompt_cache_size: usize,
+
+    /// Number of threads used for generation (defaults to the llama.cpp default)
+    #[arg(long)]
+    pub threads: Option<i32>,
+
+    /// Number of threads used for prompt processing (defaults to --threads, then to the llama.cpp default)
+    #[arg(long)]
+    pub threads_batch: Option<i32>,
+
+    /// Maximum number of prompt tokens processed in a single batch
+    #[arg(long, default_value_t = 2048)]
+    pub batch_size: u32,
+
+    /// Use a fixed context size instead of sizing it for each prompt
+    #[arg(long, conflicts_with = "max_ctx_size")]
+    pub ctx_size: Option<u32>,
+
+    /// Upper limit for the context size (defaults to the context length of the model)
+    #[arg(long)]
+    pub max_ctx_size: Option<u32>,
+
+    /// Number of layers to offload to the GPU (defaults to all layers)
+    #[arg(long)]
+    pub gpu_layers: Option<u32>,
+
+    /// Do not memory-map the model file
+    #[arg(long)]
+    pub no_mmap: bool,
+
+    /// Lock the model in memory
+    #[arg(long)]
+    pub mlock: bool,
+
+    /// Enable flash attention
+    #[arg(long)]
+    pub flash_attn: bool,
+
+    /// Maximum number of requests waiting for each model (0 = unlimited)
+    #[arg(long, default_value_t = 100)]
+    pub max_queue: usize,
+
+    /// Retry a failed generation once on a fresh context, without the prompt cache
+    #[arg(long)]
+    pub retry_failed: bool,
+}
+
+impl LLMOptions {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.threads.is_some_and(|t| t < 1) || self.threads_batch.is_some_and(|t| t < 1) {
+            return Err("threads must be greater than 0".to_string());
+        }
+        if self.batch_size == 0 {
+            return Err("batch_size must be greater than 0".to_string());
+        }
+        if self.ctx_size == Some(0) || self.max_ctx_size == Some(0) {
+            return Err("context size must be greater than 0".to_string());
+        }
+        Ok(())
+    }
+
+    /// None to keep the llama.cpp default
+    pub fn n_threads_batch(&self) -> Option<i32> {
+        self.threads_batch.or(self.threads)
+    }
+
+    pub fn n_gpu_layers(&self) -> u32 {
+        if self.cpu || !cfg!(any(feature = "cuda", feature = "vulkan")) {
+            0
+        } else {
+            self.gpu_layers.unwrap_or(9999)
+        }
+    }
+
+    /// Prints the effective settings
+    pub fn print(&self) {
+        let on_off = |b: bool| if b { "on" } else { "off" };
+        let ctx_size = match (self.ctx_size, self.max_ctx_size) {
+            (Some(size), _) => size.to_string(),
+            (None, Some(max)) => format!("auto (max {})", max),
+            (None, None) => "auto".to_string()
+        };
+        let gpu_layers = match self.n_gpu_layers() {
+            9999 => "all".to_string(),
+            n => n.to_string()
+        };
+
+        let threads = |threads: Option<i32>| threads.map_or("default".to_string(), |t| t.to_string());
+        println!("Threads: {} (batch: {})", threads(self.threads), threads(self.n_threads_batch()));
+        println!("Batch size: {}", self.batch_size);
+        println!("Context size: {}", ctx_size);
+        println!("GPU layers: {}", gpu_layers);
+        println!("mmap: {}, mlock: {}", on_off(!self.no_mmap), on_off(self.mlock));
+        println!("Flash attention: {}", on_off(self.flash_attn));
+        if self.no_prompt_cache {
+            println!("Prompt cache: off");
+        } else {
+            println!("Prompt cache: on ({} MiB)", self.prompt_cache_size);
+        }
+        println!("Max queue: {}", if self.max_queue == 0 { "unlimited".to_string() } else { self.max_queue.to_string() });
+        println!("Retry failed generations: {}", on_off(self.retry_failed));
+    }
+}
+
+/// What `/translate` answers when the LLM fails to translate a text
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum InferenceFallback {
+    /// Respond with an error (500, or 503 when shutting down)
+    #[default]
+    Error,
+    /// Respond with the source text, as if it was the translation
+    Source,
+}
 
 pub struct LLM {
-    backend: LlamaBackend,
+    backend: Arc<LlamaBackend>,
     model: LlamaModel,
-    prompt_lock: Mutex<bool>
+    template: PromptTemplate,
+    options: LLMOptions,
+    prompt_cache: Mutex<PromptCache>,
+    queue: InferenceQueue
+}
+
+/// Llama state after evaluating the tokens shared by all prompts
+/// with the same system prompt
+struct PrefixState {
+    tokens: Vec<LlamaToken>,
+    state: Vec<u8>
+}
+
+impl PrefixState {
+    fn size(&self) -> usize {
+        self.state.len() + self.tokens.len() * std::mem::size_of::<LlamaToken>()
+    }
+}
+
+/// Evaluated system prompts, bounded by the size of their states
+struct PromptCache {
+    /// Least recently used first
+    entries: Vec<(String, Arc<PrefixState>)>,
+    size: usize,
+    max_size: usize
+}
+
+impl PromptCache {
+    fn new(max_size: usize) -> PromptCache {
+        PromptCache { entries: Vec::new(), size: 0, max_size }
+    }
+
+    fn get(&mut self, system: &str) -> Option<Arc<PrefixState>> {
+        let pos = self.entries.iter().position(|(key, _)| key == system)?;
+        let entry = self.entries.remove(pos);
+        let prefix = entry.1.clone();
+        self.entries.push(entry);
+        Some(prefix)
+    }
+
+    fn insert(&mut self, system: &str, prefix: PrefixState) {
+        if let Some(pos) = self.entries.iter().position(|(key, _)| key == system) {
+            let (_, old) = self.entries.remove(pos);
+            self.size -= old.size();
+        }
+        let size = prefix.size();
+        if size > self.max_size {
+            return;
+        }
+        while self.size + size > self.max_size {
+            let (_, old) = self.entries.remove(0);
+            self.size -= old.size();
+        }
+        self.size += size;
+        self.entries.push((system.to_string(), Arc::new(prefix)));
+    }
+}
+
+// Placeholder used to locate the user message in the formatted prompt
+const USER_PLACEHOLDER: &str = "\u{1}LTENGINE_USER\u{1}";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    EndOfGeneration,
+    MaxTokens,
+    RepetitionLoop,
+    ContextFull
+}
+
+pub struct Completion {
+    pub text: String,
+    pub stop_reason: StopReason
+}
+
+impl Completion {
+    pub fn truncated(&self) -> bool {
+        self.stop_reason != StopReason::EndOfGeneration
+    }
+}
+
+// Longest n-gram checked by the repetition guard
+const LOOP_MAX_NGRAM: usize = 16;
+// Minimum number of repeated tokens before we consider the output to be looping
+const LOOP_MIN_SPAN: usize = 16;
+
+/// Number of consecutive occurrences of `ngram` at the end of `tokens`
+fn trailing_run(tokens: &[LlamaToken], ngram: &[LlamaToken]) -> usize {
+    tokens.rchunks_exact(ngram.len()).take_while(|chunk| *chunk == ngram).count()
+}
+
+/// Longest number of consecutive occurrences of `ngram` in `tokens`
+fn longest_run(tokens: &[LlamaToken], ngram: &[LlamaToken]) -> usize {
+    (0..tokens.len())
+        .map(|start| tokens[start..].chunks_exact(ngram.len()).take_while(|chunk| *chunk == ngram).count())
+        .max()
+        .unwrap_or(0)
+}
+
+/// Checks whether the tail of `tokens` is the same n-gram repeated over and over,
+/// more times than it is repeated in `source` (e.g. table rules or ellipses copied from the input).
+/// Returns the number of trailing tokens to drop so that only the occurrences found in the source
+/// (or a single one) are kept.
+fn find_repetition_loop(tokens: &[LlamaToken], source: &[LlamaToken]) -> Option<usize> {
+    for n in 1..=LOOP_MAX_NGRAM {
+        let reps = LOOP_MIN_SPAN.div_ceil(n).max(3);
+        if tokens.len() < n * reps {
+            continue;
+        }
+
+        let ngram = &tokens[tokens.len() - n..];
+        let run = trailing_run(tokens, ngram);
+        if run < reps {
+            continue;
+        }
+
+        let allowed = longest_run(source, ngram);
+        if run > allowed {
+            return Some((run - allowed.max(1)) * n);
+        }
+    }
+
+    None
 }
 
 pub struct LLMContext<'a>{
@@ -26,34 +278,45 @@ pub struct LLMContext<'a>{
 }
 
 impl LLM {
-    pub fn new(model_path: PathBuf, cpu: bool, verbose: bool) -> Result<Self> {
-        if !verbose{
-            send_logs_to_tracing(LogOptions::default().with_logs_enabled(false));
-        }
-        
-        let backend = LlamaBackend::init()?;
-
-        let model_params = {
-            if !cpu && cfg!(any(feature = "cuda", feature = "vulkan")) {
-                LlamaModelParams::default().with_n_gpu_layers(9999)
-            } else {
-                LlamaModelParams::default()
-            }
-        };
+    pub fn new(backend: Arc<LlamaBackend>, model_path: PathBuf, template: PromptTemplate, options: LLMOptions) -> Result<Self> {
+        let model_params = LlamaModelParams::default()
+            .with_n_gpu_layers(options.n_gpu_layers())
+            .with_use_mmap(!options.no_mmap)
+            .with_use_mlock(options.mlock);
 
         let model = LlamaModel::load_from_file(&backend, model_path, &model_params)
             .with_context(|| "Unable to load model")?;
         
-        Ok(LLM { backend, model, prompt_lock: Mutex::new(true) })
+        let queue = InferenceQueue::new(options.max_queue);
+        let prompt_cache = Mutex::new(PromptCache::new(options.prompt_cache_size.saturating_mul(1024 * 1024)));
+        Ok(LLM { backend, model, template, options, prompt_cache, queue })
     }
 
-    pub fn create_context(&self, ctx_size: i32) -> Result<LLMContext>{
-        let ctx_params =
-            LlamaContextParams::default().with_n_ctx(Some(NonZeroU32::new(ctx_size as u32).unwrap()));
+    /// Takes a place in the queue of this model, to be passed to `run_prompt`
+    pub fn enqueue(&self, client: &str, priority: Priority) -> Result<Ticket, QueueFull> {
+        self.queue.enqueue(client, priority)
+    }
 
-        // Use all threads
-        // ctx_params = ctx_params.with_n_threads(threads);
-        // ctx_params = ctx_params.with_n_threads_batch(threads_batch);
+    /// How long the running job has been using the model
+    pub fn running_for(&self) -> Option<Duration> {
+        self.queue.running_for()
+    }
+
+    pub fn prompt_template(&self) -> &PromptTemplate {
+        &self.template
+    }
+
+    pub fn create_context(&self, ctx_size: i32) -> Result<LLMContext>{
+        let mut ctx_params = LlamaContextParams::default()
+            .with_n_ctx(Some(NonZeroU32::new(ctx_size as u32).ok_or_else(|| anyhow!("Invalid context size: {}", ctx_size))?))
+            .with_n_batch(self.options.batch_size)
+            .with_flash_attention(self.options.flash_attn);
+        if let Some(threads) = self.options.threads {
+            ctx_params = ctx_params.with_n_threads(threads);
+        }
+        if let Some(threads) = self.options.n_threads_batch() {
+            ctx_params = ctx_params.with_n_threads_batch(threads);
+        }
 
         let ctx = self.model
             .new_context(&self.backend, ctx_params)
@@ -61,12 +324,71 @@ impl LLM {
         Ok(LLMContext{ llm: self, ctx, ctx_size })
     }
 
-    pub fn run_prompt(&self, system: String, user: String) -> Result<String>{
-        let tmpl = self.model.chat_template(None)?;
-        let llm_input = self.model.apply_chat_template(&tmpl, &[
-            LlamaChatMessage::new("system".to_string(), system)?,
-            LlamaChatMessage::new("user".to_string(), user)?
-        ], true)?;
+    pub fn count_tokens(&self, text: &str) -> Result<usize> {
+        let tokens = self.model
+            .str_to_token(text, AddBos::Never)
+            .with_context(|| "Failed to tokenize input")?;
+        Ok(tokens.len())
+    }
+
+    fn format_prompt(&self, system: &str, user: &str) -> Result<String> {
+        if self.template.raw {
+            return Ok(if system.is_empty() { user.to_string() } else { format!("{}\n\n{}", system, user) });
+        }
+
+        // Not all models ship with a chat template
+        let tmpl = match self.model.chat_template(None) {
+            Ok(tmpl) => tmpl,
+            Err(_) => LlamaChatTemplate::new(self.template.chat_template_name())?
+        };
+
+        let mut messages = Vec::with_capacity(2);
+        if !system.is_empty() {
+            messages.push(LlamaChatMessage::new("system".to_string(), system.to_string())?);
+        }
+        messages.push(LlamaChatMessage::new("user".to_string(), user.to_string())?);
+        Ok(self.model.apply_chat_template(&tmpl, &messages, true)?)
+    }
+
+    /// Tokens of `tokens_list` that only depend on the system prompt
+    fn prefix_tokens(&self, system: &str, tokens_list: &[LlamaToken]) -> Result<Vec<LlamaToken>> {
+        let formatted = self.format_prompt(system, USER_PLACEHOLDER)?;
+        let Some(pos) = formatted.find(USER_PLACEHOLDER) else {
+            return Ok(Vec::new());
+        };
+
+        // Tokens at the boundary with the user message can merge differently,
+        // so only keep the part that matches the actual prompt
+        let prefix = self.model.str_to_token(&formatted[..pos], AddBos::Always)?;
+        let common = prefix.iter().zip(tokens_list).take_while(|(a, b)| a == b).count();
+
+        // Leave at least one token to evaluate so that we get logits to sample from
+        Ok(tokens_list[..common.min(tokens_list.len() - 1)].to_vec())
+    }
+
+    /// Context size for a prompt of `n_tokens` tokens
+    fn context_size(&self, n_tokens: usize, max_tokens: Option<u32>) -> Result<i32> {
+        let n_tokens = n_tokens as u32;
+        let limit = self.options.ctx_size.or(self.options.max_ctx_size);
+        if let Some(limit) = limit.filter(|limit| n_tokens >= *limit) {
+            return Err(anyhow!("The prompt ({} tokens) does not fit in the context size ({})", n_tokens, limit));
+        }
+
+        let size = match (self.options.ctx_size, max_tokens) {
+            (Some(size), _) => Some(size),
+            (None, Some(max_tokens)) => n_tokens.checked_add(max_tokens),
+            (None, None) => n_tokens.checked_mul(3)
+        };
+        let size = limit.map_or(size, |limit| size.map(|size| size.min(limit)))
+            .and_then(|size| i32::try_from(size).ok())
+            .ok_or_else(|| anyhow!("The prompt ({} tokens) is too long", n_tokens))?;
+        Ok(size)
+    }
+
+    /// Waits for `ticket` to come up in the queue and generates the completion
+    pub fn run_prompt(&self, ticket: Ticket, prompt: Prompt, sampling: &SamplingConfig) -> Result<Completion>{
+        let Prompt { system, user, cacheable } = prompt;
+        let llm_input = self.format_prompt(&system, &user)?;
 
         let tokens_list = self.model
             .str_to_token(&llm_input
@@ -75,57 +397,142 @@ impl LLM {
         // for token in &tokens_list {
         //     eprint!("{} {} | ", self.model.token_to_str(*token, Special::Tokenize)?, token);
         // }
-        let ctx_size: i32 = tokens_list.len() as i32 * 3;
+        let ctx_size = self.context_size(tokens_list.len(), sampling.max_tokens)?;
+
+        let use_cache = !self.options.no_prompt_cache && cacheable && !system.is_empty();
+        let cached = if use_cache {
+            self.prompt_cache.lock().unwrap().get(&system)
+                .filter(|prefix| tokens_list.starts_with(&prefix.tokens) && prefix.tokens.len() < tokens_list.len())
+        } else {
+            None
+        };
+
+        // The llama bindings (or llama itself?) do not appear to be totally thread-safe
+        // as garbage starts to come out when we run inference in parallel,
+        // so requests are processed one at a time in queue order.
+        let permit = ticket.wait()?;
+        Span::current().record("queue_wait_ms", permit.waited.as_millis() as u64);
+
+        let mut result = self.generate(&tokens_list, ctx_size, cached, &system, use_cache, sampling);
+        if let Err(err) = &result {
+            if self.options.retry_failed && !self.queue.is_closed() {
+                warn!("Generation failed, retrying on a fresh context: {:#}", err);
+                METRICS.record_inference_retry();
+                result = self.generate(&tokens_list, ctx_size, None, &system, false, sampling);
+            }
+        }
+        result
+    }
+
+    pub fn queue(&self) -> &InferenceQueue {
+        &self.queue
+    }
+
+    /// Generates the completion of `tokens_list`, reusing the evaluated system prompt if `cached`
+    /// and caching it otherwise if `use_cache`
+    fn generate(&self, tokens_list: &[LlamaToken], ctx_size: i32, cached: Option<Arc<PrefixState>>, system: &str, use_cache: bool, sampling: &SamplingConfig) -> Result<Completion> {
+        if let Some(prefix) = cached {
+            let mut ctx = self.create_context(ctx_size)?;
+            if ctx.restore_state(&prefix.state) {
+                METRICS.record_prompt_cache(true);
+                let n_past = prefix.tokens.len();
+                return ctx.process(tokens_list[n_past..].to_vec(), n_past as i32, sampling);
+            }
+        }
+
+        if use_cache {
+            METRICS.record_prompt_cache(false);
+        }
+
         let mut ctx = self.create_context(ctx_size)?;
-        {
-            // TODO: The llama bindings (or llama itself?) do not appear to be totally thread-safe
-            // as garbage starts to come out when we run inference in parallel
-            // this might need to be investigated and fixed. For now we lock and process requests
-            // one at a time.
-            // TODO: consider locking with a timeout: https://docs.rs/parking_lot/latest/parking_lot/type.Mutex.html#method.try_lock_for
-            let _lock = self.prompt_lock.lock();
-            ctx.process(tokens_list)
+        let prefix = if use_cache { self.prefix_tokens(system, tokens_list)? } else { Vec::new() };
+        if prefix.is_empty() {
+            return ctx.process(tokens_list.to_vec(), 0, sampling);
         }
+
+        let state = ctx.eval_prefix(&prefix)?;
+        self.prompt_cache.lock().unwrap().insert(system, PrefixState { tokens: prefix.clone(), state });
+
+        let n_past = prefix.len();
+        ctx.process(tokens_list[n_past..].to_vec(), n_past as i32, sampling)
     }
 }
 
 impl LLMContext<'_>{
-    pub fn process(&mut self, tokens_list: Vec<LlamaToken>) -> Result<String>{
-        // let ctx_size: i32 = tokens_list.len() as i32 * 3;
-        
-        // We use this object to submit token data for decoding
-        let mut batch = LlamaBatch::new(self.ctx_size.try_into()?, 1);
+    /// Evaluates `tokens` from the start of the sequence and returns the resulting llama state
+    pub fn eval_prefix(&mut self, tokens: &[LlamaToken]) -> Result<Vec<u8>> {
+        self.decode_prompt(tokens, 0, false)?;
+
+        let mut state = vec![0u8; self.ctx.get_state_size()];
+        // SAFETY: llama writes at most get_state_size() bytes, the size of the buffer,
+        // and the context cannot change between the two calls since we hold &mut self
+        let size = unsafe { self.ctx.copy_state_data(state.as_mut_ptr()) };
+        state.truncate(size);
+        Ok(state)
+    }
 
-        let last_index: i32 = (tokens_list.len() - 1) as i32;
-        for (i, token) in (0_i32..).zip(tokens_list.into_iter()) {
-            // llama_decode will output logits only for the last token of the prompt
-            let is_last = i == last_index;
-            batch.add(token, i, &[0], is_last)?;
+    /// Decodes `tokens` starting at position `n_past`, at most `batch_size` tokens at a time.
+    /// Returns the last batch, with logits for its last token if `logits` is set.
+    fn decode_prompt(&mut self, tokens: &[LlamaToken], n_past: i32, logits: bool) -> Result<LlamaBatch> {
+        let n_batch = self.llm.options.batch_size as usize;
+        let mut batch = LlamaBatch::new(n_batch, 1);
+        let mut pos = n_past;
+
+        let n_chunks = tokens.len().div_ceil(n_batch);
+        for (c, chunk) in tokens.chunks(n_batch).enumerate() {
+            batch.clear();
+            for (i, token) in chunk.iter().enumerate() {
+                let is_last = c == n_chunks - 1 && i == chunk.len() - 1;
+                batch.add(*token, pos, &[0], logits && is_last)?;
+                pos += 1;
+            }
+
+            self.ctx.decode(&mut batch)
+                .with_context(|| "llama_decode() failed")?;
         }
 
-        self.ctx.decode(&mut batch)
-            .with_context(|| "llama_decode() failed")?;
+        Ok(batch)
+    }
+
+    /// Loads a state saved by `eval_prefix`, returns false if it could not be restored
+    pub fn restore_state(&mut self, state: &[u8]) -> bool {
+        // SAFETY: llama only reads `state`, within its length. The state was saved by
+        // eval_prefix with the same model (the prompt cache belongs to the LLM), and a state
+        // that does not fit this context (e.g. a smaller context size) is rejected by llama,
+        // which then reads less than the whole state
+        let read = unsafe { self.ctx.set_state_data(state) };
+        read == state.len()
+    }
+
+    /// Evaluates `tokens_list` (starting at position `n_past`) and generates the completion
+    pub fn process(&mut self, tokens_list: Vec<LlamaToken>, n_past: i32, sampling: &SamplingConfig) -> Result<Completion>{
+        // llama_decode will output logits only for the last token of the prompt
+        let mut batch = self.decode_prompt(&tokens_list, n_past, true)?;
 
-        let mut n_cur = batch.n_tokens();
+        let mut n_cur = n_past + tokens_list.len() as i32;
+        let start = Instant::now();
 
         let mut decoder = encoding_rs::UTF_8.new_decoder();
-        let seq_breakers = vec![b"\n", b":", b"\"", b"*"];
-
-        let mut sampler = LlamaSampler::chain_simple([
-            LlamaSampler::penalties(64, 1.0, 0.0, 0.0),
-            LlamaSampler::dry(&self.llm.model, 0.0, 1.75, 2, -1, seq_breakers),
-            LlamaSampler::top_k(40),
-            LlamaSampler::typical(1.0, 0),
-            LlamaSampler::top_p(0.95, 0),
-            LlamaSampler::min_p(0.05, 0),
-            LlamaSampler::xtc(0.0, 0.1, 0, 42),
-            LlamaSampler::temp_ext(0.0, 0.0, 1.0),
-            LlamaSampler::dist(42)
-        ]);
+        let mut sampler = sampling.build_sampler(&self.llm.model);
 
         let mut output = String::new();
+        let mut n_generated: u32 = 0;
+        let mut stop_reason = StopReason::ContextFull;
+
+        // Generated tokens and the length of the output before each of them,
+        // used to cut the output back when the model starts looping
+        let mut generated: Vec<LlamaToken> = Vec::new();
+        let mut offsets: Vec<usize> = Vec::new();
 
         while n_cur <= self.ctx_size {
+            if self.llm.queue.is_closed() {
+                return Err(QueueClosed.into());
+            }
+
+            if sampling.max_tokens.is_some_and(|max| n_generated >= max) {
+                stop_reason = StopReason::MaxTokens;
+                break;
+            }
 
             // sample the next token
             {
@@ -135,6 +542,7 @@ impl LLMContext<'_>{
 
                 // is it an end of stream?
                 if self.llm.model.is_eog_token(token) {
+                    stop_reason = StopReason::EndOfGeneration;
                     break;
                 }
                     
@@ -142,17 +550,132 @@ impl LLMContext<'_>{
                 // use `Decoder.decode_to_string()` to avoid the intermediate buffer
                 let mut output_string = String::with_capacity(32);
                 let _decode_result = decoder.decode_to_string(&output_bytes, &mut output_string, false);
+                offsets.push(output.len());
+                generated.push(token);
                 output.push_str(&output_string);
 
+                if let Some(drop) = find_repetition_loop(&generated, &tokens_list) {
+                    output.truncate(offsets[generated.len() - drop]);
+                    stop_reason = StopReason::RepetitionLoop;
+                    break;
+                }
+
                 batch.clear();
                 batch.add(token, n_cur, &[0], true)?;
             }
 
             n_cur += 1;
+            n_generated += 1;
 
             self.ctx.decode(&mut batch).with_context(|| "Failed to eval")?;
         }
 
-        Ok(output)
+        let elapsed = start.elapsed();
+        METRICS.record_generation(n_past as usize + tokens_list.len(), n_generated as usize, elapsed);
+
+        let span = Span::current();
+        span.record("prompt_tokens", n_past as usize + tokens_list.len());
+        span.record("generated_tokens", n_generated);
+        span.record("decode_ms", elapsed.as_millis() as u64);
+        debug!(cached_tokens = n_past, stop_reason = ?stop_reason, "generation finished");
+
+        Ok(Completion { text: output, stop_reason })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(ids: &[i32]) -> Vec<LlamaToken> {
+        ids.iter().map(|id| LlamaToken(*id)).collect()
+    }
+
+    #[test]
+    fn detects_repeated_token() {
+        let mut output = tokens(&[1, 2, 3]);
+        output.extend(tokens(&[7; LOOP_MIN_SPAN]));
+        assert_eq!(find_repetition_loop(&output, &[]), Some(LOOP_MIN_SPAN - 1));
+    }
+
+    #[test]
+    fn detects_repeated_ngram() {
+        let mut output = tokens(&[1, 2, 3]);
+        for _ in 0..8 {
+            output.extend(tokens(&[4, 5]));
+        }
+        assert_eq!(find_repetition_loop(&output, &tokens(&[1, 2, 3])), Some(14));
+    }
+
+    #[test]
+    fn ignores_short_repetitions() {
+        let output = tokens(&[1, 2, 7, 7, 7, 7, 7, 3, 4, 5, 4, 5, 4, 5]);
+        assert_eq!(find_repetition_loop(&output, &[]), None);
+    }
+
+    #[test]
+    fn allows_repetitions_found_in_source() {
+        // e.g. a table rule `--------------------` copied from the input
+        let mut source = tokens(&[10, 11]);
+        source.extend(tokens(&[7; 20]));
+        source.extend(tokens(&[12]));
+
+        let mut output = tokens(&[20]);
+        output.extend(tokens(&[7; 20]));
+        assert_eq!(find_repetition_loop(&output, &source), None);
+    }
+
+    #[test]
+    fn cuts_repetitions_beyond_source() {
+        let source = tokens(&[7; 20]);
+        let mut output = tokens(&[20]);
+        output.extend(tokens(&[7; 21]));
+        assert_eq!(find_repetition_loop(&output, &source), Some(1));
+    }
+
+    #[test]
+    fn allows_repeated_ngram_found_in_source() {
+        // e.g. "ha ha ha ha ha ha ha ha ha"
+        let mut source = Vec::new();
+        for _ in 0..9 {
+            source.extend(tokens(&[30, 31]));
+        }
+        let mut output = tokens(&[1]);
+        for _ in 0..9 {
+            output.extend(tokens(&[30, 31]));
+        }
+        assert_eq!(find_repetition_loop(&output, &source), None);
+    }
+
+    fn prefix(size: usize) -> PrefixState {
+        PrefixState { tokens: Vec::new(), state: vec![0; size] }
+    }
+
+    #[test]
+    fn prompt_cache_evicts_least_recently_used() {
+        let mut cache = PromptCache::new(300);
+        cache.insert("a", prefix(100));
+        cache.insert("b", prefix(100));
+        cache.insert("c", prefix(100));
+        assert!(cache.get("a").is_some());
+
+        cache.insert("d", prefix(100));
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("c").is_some());
+        assert!(cache.get("d").is_some());
+        assert_eq!(cache.size, 300);
+    }
+
+    #[test]
+    fn prompt_cache_skips_states_larger_than_limit() {
+        let mut cache = PromptCache::new(300);
+        cache.insert("a", prefix(100));
+        cache.insert("b", prefix(301));
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("b").is_none());
+
+        cache.insert("a", prefix(250));
+        assert_eq!(cache.size, 250);
     }
 }
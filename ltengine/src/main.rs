@@ -2,11 +2,21 @@ use actix_web::{
     get, post, web, App, HttpRequest, HttpResponse, 
     HttpServer, Responder, http::header, FromRequest
 };
+use actix_web::dev::{Service, ServiceRequest};
+use actix_web::http::header::{HeaderName, HeaderValue};
 use actix_multipart::form::{MultipartForm, text::Text as MPText};
 use actix_web_static_files::ResourceFiles;
+use std::collections::HashSet;
+use std::net::IpAddr;
 use std::sync::Arc;
-use clap::Parser;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+use clap::parser::ValueSource;
 use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, OpenApi, ToSchema};
+use utoipa_swagger_ui::SwaggerUi;
+use tracing::{error, info, warn, Instrument};
 
 mod error_response;
 mod languages;
@@ -14,18 +24,54 @@ mod models;
 mod llm;
 mod banner;
 mod prompt;
+mod sampling;
+mod pool;
+mod routing;
+mod metrics;
+mod health;
+mod logging;
+mod api_keys;
+mod rate_limit;
+mod queue;
+mod config;
+mod tls;
+mod cors;
+mod suggestions;
+mod openapi;
+mod lexilang;
+mod shutdown;
 
-use languages::{detect_lang, get_language_from_code, LANGUAGES};
-use error_response::ErrorResponse;
-use models::{MODELS, load_model};
+use languages::{detect_lang, get_language_from_code, output_token_ratio, print_detection_benchmark, Language};
+use error_response::{ErrorCode, ErrorResponse};
+use models::{DownloadOptions, ModelRegistry};
 use banner::print_banner;
 use prompt::PromptBuilder;
+use sampling::{output_token_budget, SamplingConfig, SamplingOverrides};
+use pool::{LoadedModel, ModelPool};
+use llm::{InferenceFallback, LLMOptions};
+use routing::{RouteRequest, RouteRule, Router};
+use metrics::METRICS;
+use health::{Degraded, Health, ServerStatus, Unhealthy};
+use logging::LogFormat;
+use api_keys::{origin_matches, ApiKeySettings, ApiKeyStore, KeyError};
+use rate_limit::{client_ip, Limits, RateLimiter};
+use queue::{Priority, QueueClosed};
+use tls::CertResolver;
+use cors::CorsOptions;
+use suggestions::{ExportFormat, SuggestionStore};
+use shutdown::{stop_on_signals, SHUTDOWN_GRACE};
+use llama_cpp_2::llama_backend::LlamaBackend;
+use llama_cpp_2::{send_logs_to_tracing, LogOptions};
 
 include!(concat!(env!("OUT_DIR"), "/generated.rs"));
 
 #[derive(Parser, Debug, Clone)]
 #[command(version, about, long_about = None)]
 struct Args {
+    /// Path to a TOML configuration file. Keys are option names (e.g. `char_limit = 2000`)
+    #[arg(long)]
+    config: Option<String>,
+
     /// Hostname to bind to
     #[arg(long, default_value = "0.0.0.0")]
     host: String,
@@ -34,39 +80,382 @@ struct Args {
     #[arg(short, long, default_value_t = 5050)]
     port: u16,
 
+    /// Additional addresses to listen on, e.g. `127.0.0.1:8080` or `[::1]:5050` (comma separated)
+    #[arg(long, value_delimiter = ',')]
+    bind: Vec<String>,
+
+    /// Also listen on this Unix domain socket
+    #[arg(long, default_value = "")]
+    unix_socket: String,
+
+    /// Path to a PEM certificate chain, enables HTTPS (reloaded on SIGHUP)
+    #[arg(long, default_value = "")]
+    tls_cert: String,
+
+    /// Path to the PEM private key of --tls-cert
+    #[arg(long, default_value = "")]
+    tls_key: String,
+
     /// Character limit for translation requests
     #[arg(long, default_value_t = 5000)]
     char_limit: usize,
 
-    /// Model to use
-    #[arg(short='m', long, value_parser = MODELS.keys().collect::<Vec<_>>(), default_value = "gemma3-4b")]
-    model: String,
+    /// Models to load, comma separated (see `ltengine models list`). The first one is the default
+    #[arg(short='m', long, default_value = "gemma3-4b", value_delimiter = ',')]
+    model: Vec<String>,
+
+    /// Route requests to a model, e.g. `pair:en/ja=gemma3-27b`, `format:html=gemma3-27b` or `min-chars:2000=gemma3-27b` (can be repeated)
+    #[arg(long = "route", value_name = "RULE")]
+    routes: Vec<RouteRule>,
+
+    /// Path to a TOML routing table mapping language pairs to models
+    #[arg(long, default_value = "")]
+    routes_file: String,
 
-    /// Path to .gguf model file
+    /// Admin key required to load and unload models at runtime (admin endpoints are disabled if empty)
+    #[arg(long, default_value = "")]
+    admin_key: String,
+
+    /// Path to a TOML file with additional model definitions
+    #[arg(long, default_value = "")]
+    models_file: String,
+
+    /// Path to .gguf model file (for the default model)
     #[arg(long, default_value = "")]
     model_file: String,
 
+    /// Only load models from the local cache, never access the network
+    #[arg(long)]
+    offline: bool,
+
+    /// Hugging Face hub cache directory (default: $HF_HOME/hub)
+    #[arg(long, default_value = "")]
+    cache_dir: String,
+
+    /// Hugging Face endpoint or mirror to download models from (default: $HF_ENDPOINT)
+    #[arg(long, default_value = "")]
+    hf_endpoint: String,
+
+    /// Skip SHA256 verification of downloaded models
+    #[arg(long)]
+    no_verify: bool,
+
+    /// Path to a prompt template file (JSON), overrides the prompt format of the default model
+    #[arg(long, default_value = "")]
+    prompt_template: String,
+
     /// Set an API key
     #[arg(long, default_value = "")]
     api_key: String,  
 
-    /// Use CPU only
+    /// Path to a JSON file storing API keys with their own limits (enables API key management)
+    #[arg(long, default_value = "")]
+    api_keys_file: String,
+
+    /// Require an API key for all requests
+    #[arg(long)]
+    require_api_key: bool,
+
+    /// Require an API key for programmatic access, unless the request origin matches this domain
+    #[arg(long, default_value = "")]
+    require_api_key_origin: String,
+
+    /// Maximum number of requests per minute per client IP or API key (0 = unlimited)
+    #[arg(long, default_value_t = 0)]
+    req_limit: u32,
+
+    /// Maximum number of characters per minute per client IP or API key (0 = unlimited)
+    #[arg(long, default_value_t = 0)]
+    char_rate_limit: u32,
+
+    /// Addresses of reverse proxies trusted to set X-Forwarded-For (comma separated)
+    #[arg(long, value_delimiter = ',')]
+    trusted_proxy: Vec<IpAddr>,
+
+
+    /// Report not ready on /ready when more requests than this are waiting for the LLM
+    #[arg(long, default_value_t = 16)]
+    ready_queue_limit: usize,
+
+    /// Report unhealthy on /health and /ready when a translation has held the model
+    /// for more than this many seconds (0 = never)
+    #[arg(long, default_value_t = 600)]
+    stall_timeout: u64,
+
+    /// Allow users to suggest better translations with /suggest
     #[arg(long)]
-    cpu: bool,
+    suggestions: bool,
+
+    /// Path to the JSON lines file storing suggestions
+    #[arg(long, default_value = "suggestions.jsonl")]
+    suggestions_file: String,
+
+    /// Answer translation requests with approved suggestions when the text matches exactly
+    #[arg(long)]
+    translation_memory: bool,
+
+    /// Response to translations that fail (see also --retry-failed)
+    #[arg(long, value_enum, default_value_t = InferenceFallback::Error)]
+    on_inference_error: InferenceFallback,
+
+    /// Seconds to wait for in-flight and queued translations to finish when shutting down
+    #[arg(long, default_value_t = 30)]
+    shutdown_timeout: u64,
+
+    /// File where translations still pending at shutdown are saved (JSON lines)
+    #[arg(long, default_value = "")]
+    pending_jobs_file: String,
 
     /// Enable verbose logging
     #[arg(short = 'v', long)]
-    verbose: bool
+    verbose: bool,
+
+    /// Log output format
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Include the text of requests and translations in the logs
+    #[arg(long)]
+    log_bodies: bool,
+
+    #[command(flatten)]
+    sampling: SamplingConfig,
+
+    #[command(flatten)]
+    llm: LLMOptions,
+
+    #[command(flatten)]
+    cors: CorsOptions,
+
+    #[command(subcommand)]
+    command: Option<Command>
+}
+
+impl Args {
+    /// Checks the options that clap cannot validate on its own
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.char_limit == 0 {
+            anyhow::bail!("--char-limit must be greater than 0");
+        }
+        if self.model.iter().all(|m| m.is_empty()) {
+            anyhow::bail!("--model must name at least one model");
+        }
+        config::validate_path("model-file", &self.model_file)?;
+        config::validate_path("prompt-template", &self.prompt_template)?;
+        config::validate_path("routes-file", &self.routes_file)?;
+        config::validate_path("models-file", &self.models_file)?;
+        if self.tls_cert.is_empty() != self.tls_key.is_empty() {
+            anyhow::bail!("--tls-cert and --tls-key must be used together");
+        }
+        if self.cors.cors_key_origins && self.api_keys_file.is_empty() {
+            anyhow::bail!("--cors-key-origins requires --api-keys-file");
+        }
+        self.cors.validate().map_err(|err| anyhow::anyhow!(err))?;
+        if !self.unix_socket.is_empty() && !cfg!(unix) {
+            anyhow::bail!("--unix-socket is not supported on this platform");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Manage models
+    Models {
+        #[command(subcommand)]
+        action: ModelsCommand
+    },
+    /// Manage API keys (requires --api-keys-file)
+    Keys {
+        #[command(subcommand)]
+        action: KeysCommand
+    },
+    /// Review and export suggestions (see --suggestions-file)
+    Suggestions {
+        #[command(subcommand)]
+        action: SuggestionsCommand
+    },
+    /// Inspect the configuration
+    Config {
+        #[command(subcommand)]
+        action: ConfigCommand
+    },
+    /// Evaluate language detection
+    Langdetect {
+        #[command(subcommand)]
+        action: LangdetectCommand
+    }
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum ModelsCommand {
+    /// List available models
+    List
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum ConfigCommand {
+    /// Print the effective configuration (command line, environment, config file and defaults)
+    Print
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Subcommand, Debug, Clone)]
+enum LangdetectCommand {
+    /// Measure the accuracy of language detection on short texts
+    Benchmark {
+        /// Test set with one `<language code><TAB><text>` per line (default: the built-in one)
+        file: Option<String>,
+    }
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum SuggestionsCommand {
+    /// List suggestions
+    List,
+    /// Export suggestions for fine-tuning or CAT tools
+    Export {
+        #[arg(long, value_enum, default_value_t = ExportFormat::Jsonl)]
+        format: ExportFormat,
+        /// Only export approved suggestions
+        #[arg(long)]
+        approved: bool,
+    },
+    /// Approve a suggestion, adding it to the translation memory
+    Approve { id: u64 },
+    /// Delete a suggestion
+    Delete { id: u64 },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum KeysCommand {
+    /// Create an API key
+    Add {
+        #[command(flatten)]
+        settings: ApiKeySettings
+    },
+    /// List API keys
+    List,
+    /// Delete an API key
+    Revoke { key: String },
+    /// Enable an API key
+    Enable { key: String },
+    /// Disable an API key without deleting it
+    Disable { key: String },
+}
+
+/// A single value, or an array of them for batch requests
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl<T> OneOrMany<T> {
+    fn as_slice(&self) -> &[T] {
+        match self {
+            OneOrMany::One(value) => std::slice::from_ref(value),
+            OneOrMany::Many(values) => values,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
 struct TranslateRequest {
-    q: Option<String>,
+    /// Text to translate (or to detect the language of), or an array of texts to translate
+    q: Option<OneOrMany<String>>,
+    /// Source language code, or `auto` to detect it
     source: Option<String>,
+    /// Target language code
     target: Option<String>,
+    /// `text` (default) or `html`
     format: Option<String>,
     api_key: Option<String>,
-    alternatives: Option<u32>
+    /// Number of alternative translations (not supported yet, always empty)
+    alternatives: Option<u32>,
+    /// Model to use instead of the routed or default one
+    model: Option<String>,
+    /// Terms and their required translations
+    glossary: Option<String>,
+    /// Text surrounding `q`, used to disambiguate it
+    context: Option<String>,
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    seed: Option<u32>,
+    /// Maximum number of tokens to generate
+    max_tokens: Option<u32>,
+    repetition_penalty: Option<f32>,
+    /// Suggested translation (`/suggest`)
+    s: Option<String>
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct TranslateResponse {
+    /// An array when `q` is one
+    translated_text: OneOrMany<String>,
+    /// Model that produced the translation
+    model: String,
+    /// The model stopped before the end of the translation
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    truncated: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    alternatives: Option<Vec<String>>,
+    /// Set when `source` is `auto`, an array when `q` is one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detected_language: Option<OneOrMany<DetectedLanguage>>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+struct DetectedLanguage {
+    language: String,
+    /// Confidence between 0 and 100
+    confidence: i32,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct SuggestResponse {
+    success: bool,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct FrontendSettings {
+    api_keys: bool,
+    char_limit: usize,
+    files_translation: bool,
+    frontend_timeout: u32,
+    key_required: bool,
+    req_limit: u32,
+    char_rate_limit: u32,
+    /// Languages selected when the frontend opens
+    language: FrontendLanguages,
+    suggestions: bool,
+    supported_files_format: Vec<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct FrontendLanguages {
+    source: FrontendLanguage,
+    target: FrontendLanguage,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct FrontendLanguage {
+    code: &'static str,
+    name: &'static str,
+}
+
+impl TranslateRequest {
+    fn sampling_overrides(&self) -> SamplingOverrides {
+        SamplingOverrides {
+            temperature: self.temperature,
+            top_p: self.top_p,
+            seed: self.seed,
+            max_tokens: self.max_tokens,
+            repetition_penalty: self.repetition_penalty,
+        }
+    }
 }
 
 #[derive(MultipartForm)]
@@ -76,17 +465,35 @@ struct MPTranslateRequest {
     target: Option<MPText<String>>,
     format: Option<MPText<String>>,
     api_key: Option<MPText<String>>,
-    alternatives: Option<MPText<u32>>
+    alternatives: Option<MPText<u32>>,
+    model: Option<MPText<String>>,
+    glossary: Option<MPText<String>>,
+    context: Option<MPText<String>>,
+    temperature: Option<MPText<f32>>,
+    top_p: Option<MPText<f32>>,
+    seed: Option<MPText<u32>>,
+    max_tokens: Option<MPText<u32>>,
+    repetition_penalty: Option<MPText<f32>>,
+    s: Option<MPText<String>>
 }
 impl MPTranslateRequest {
     fn into_translate_request(self) -> TranslateRequest {
         TranslateRequest {
-            q: self.q.map(|v| v.into_inner()),
+            q: self.q.map(|v| OneOrMany::One(v.into_inner())),
             source: self.source.map(|v| v.into_inner()),
             target: self.target.map(|v| v.into_inner()),
             format: self.format.map(|v| v.into_inner()),
             api_key: self.api_key.map(|v| v.into_inner()),
             alternatives: self.alternatives.map(|v| v.into_inner()),
+            model: self.model.map(|v| v.into_inner()),
+            glossary: self.glossary.map(|v| v.into_inner()),
+            context: self.context.map(|v| v.into_inner()),
+            temperature: self.temperature.map(|v| v.into_inner()),
+            top_p: self.top_p.map(|v| v.into_inner()),
+            seed: self.seed.map(|v| v.into_inner()),
+            max_tokens: self.max_tokens.map(|v| v.into_inner()),
+            repetition_penalty: self.repetition_penalty.map(|v| v.into_inner()),
+            s: self.s.map(|v| v.into_inner()),
         }
     }
 }
@@ -105,40 +512,159 @@ async fn parse_payload(req: HttpRequest, payload: web::Payload) -> Result<Transl
         let form = MultipartForm::<MPTranslateRequest>::from_request(&req, &mut payload.into_inner()).await?;
         body = form.into_inner().into_translate_request();
     } else {
-        return Err(ErrorResponse{ error: "Unsupported content-type".to_string(), status: 400 });
+        return Err(ErrorResponse{ error: "Unsupported content-type".to_string(), status: 400, code: ErrorCode::InvalidRequest, retry_after: None });
     }
 
     return Ok(body);
 }
 
-fn check_params(body: &TranslateRequest, args: &Args, required_params: &[(&str, &Option<String>)]) -> Result<bool, ErrorResponse> {
-    // Validate required params
+/// Information about the client that sent a request
+struct Client {
+    origin: Option<String>,
+    ip: Option<IpAddr>,
+}
+
+/// Identity of a client for rate limits and queue fairness
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum ClientId {
+    /// A valid API key (--api-key or one of --api-keys-file)
+    Key(String),
+    /// Address of a client without a valid key
+    Ip(IpAddr),
+    /// Neither (e.g. on the Unix socket without X-Forwarded-For)
+    Unknown,
+}
+
+impl std::fmt::Display for ClientId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ClientId::Key(key) => write!(f, "key:{}", key),
+            ClientId::Ip(ip) => write!(f, "ip:{}", ip),
+            ClientId::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+fn client_info(req: &HttpRequest, args: &Args) -> Client {
+    Client {
+        origin: req.headers().get(header::ORIGIN)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string()),
+        ip: client_ip(req, &args.trusted_proxy),
+    }
+}
+
+/// Validates a request and returns the id of its client (API key or address)
+fn check_params(body: &TranslateRequest, args: &Args, keys: Option<&ApiKeyStore>, limiter: &RateLimiter, client: &Client, required_params: &[(&str, &Option<String>)]) -> Result<ClientId, ErrorResponse> {
+    // Validate required params, q is always required
+    if body.q.as_ref().is_none_or(|q| q.as_slice().iter().all(|text| text.trim().is_empty())) {
+        return Err(ErrorResponse {
+            error: "Invalid request: missing q parameter".to_string(),
+            status: 400,
+            code: ErrorCode::MissingParameter,
+            retry_after: None,
+        });
+    }
     for (key, value) in required_params {
         if value.as_ref().is_none_or(|v| v.trim().is_empty()) {
             return Err(ErrorResponse {
                 error: format!("Invalid request: missing {} parameter", key),
                 status: 400,
+                code: ErrorCode::MissingParameter,
+                retry_after: None,
             });
         }
     }
     
     // Check key
-    if !args.api_key.is_empty() && body.api_key.as_ref().is_none_or(|key| *key != args.api_key) {
-        return Err(ErrorResponse {
-            error: format!("Invalid API key"),
-            status: 403,
-        });
+    let api_key = body.api_key.as_deref().filter(|key| !key.is_empty());
+    let origin = client.origin.as_deref();
+    let mut char_limit = args.char_limit;
+    let mut limits = limiter.limits();
+    // Keys that passed the checks, which rate limit by key instead of address
+    let mut valid_key = None;
+
+    if !args.api_key.is_empty() && api_key == Some(args.api_key.as_str()) {
+        // Key set on the command line, not rate limited
+        limits = Limits::default();
+        valid_key = api_key;
+    } else if let (Some(keys), Some(api_key)) = (keys, api_key) {
+        let key = keys.authorize(api_key, origin).map_err(|err| match err {
+            KeyError::Invalid => ErrorResponse {
+                error: "Invalid API key".to_string(),
+                status: 403,
+                code: ErrorCode::InvalidApiKey,
+                retry_after: None,
+            },
+            KeyError::Origin => ErrorResponse {
+                error: "This API key cannot be used from this origin".to_string(),
+                status: 403,
+                code: ErrorCode::InvalidApiKey,
+                retry_after: None,
+            },
+        })?;
+        char_limit = key.char_limit.unwrap_or(char_limit);
+        limits.requests = key.req_limit.or(limits.requests);
+        valid_key = Some(api_key);
+    } else {
+        let origin_allowed = !args.require_api_key_origin.is_empty()
+            && origin.is_some_and(|o| origin_matches(o, &args.require_api_key_origin));
+        let key_required = !args.api_key.is_empty()
+            || args.require_api_key
+            || !args.require_api_key_origin.is_empty();
+
+        if key_required && !origin_allowed {
+            return Err(ErrorResponse {
+                error: format!("Invalid API key"),
+                status: 403,
+                code: ErrorCode::InvalidApiKey,
+                retry_after: None,
+            });
+        }
     }
 
-    let q = body.q.as_ref().unwrap();
-    if q.len() > args.char_limit {
+    // The glossary and context end up in the prompt and suggestions are stored,
+    // so they count toward the limits too
+    let texts = body.q.as_ref().unwrap().as_slice().iter();
+    let extra = [&body.glossary, &body.context, &body.s].into_iter().flatten();
+    let len = texts.clone().chain(extra.clone()).map(String::len).sum::<usize>();
+    let chars = texts.chain(extra).map(|s| s.chars().count()).sum::<usize>();
+    if len > char_limit {
         return Err(ErrorResponse {
-            error: format!("Invalid request: request ({}) exceeds text limit ({})", q.len(), args.char_limit),
+            error: format!("Invalid request: request ({}) exceeds text limit ({})", len, char_limit),
             status: 400,
+            code: ErrorCode::TextTooLong,
+            retry_after: None,
         });
     }
 
-    Ok(true)
+    // Rate limit by API key, or by address for anonymous requests
+    let client_id = match (valid_key, client.ip) {
+        (Some(key), _) => ClientId::Key(key.to_string()),
+        (None, Some(ip)) => ClientId::Ip(ip),
+        (None, None) => ClientId::Unknown
+    };
+    limiter.check(&client_id.to_string(), limits, chars).map_err(|seconds| ErrorResponse {
+        error: format!("Too many requests, retry in {} seconds", seconds),
+        status: 429,
+        code: ErrorCode::RateLimited,
+        retry_after: Some(seconds),
+    })?;
+
+    Ok(client_id)
+}
+
+/// The text of endpoints that do not take batches
+fn single_text(q: Option<OneOrMany<String>>) -> Result<String, ErrorResponse> {
+    match q {
+        Some(OneOrMany::One(text)) => Ok(text),
+        _ => Err(ErrorResponse {
+            error: "Invalid request: q must be a string".to_string(),
+            status: 400,
+            code: ErrorCode::InvalidRequest,
+            retry_after: None,
+        })
+    }
 }
 
 fn improve_formatting(q: &String, translation: &String) -> String {
@@ -188,20 +714,33 @@ fn improve_formatting(q: &String, translation: &String) -> String {
     result.trim().to_string()
 }
 
+#[utoipa::path(
+    tag = "translate",
+    request_body(content(
+        (TranslateRequest = "application/json"),
+        (TranslateRequest = "application/x-www-form-urlencoded"),
+        (TranslateRequest = "multipart/form-data")
+    )),
+    responses(
+        (status = 200, description = "Detected languages", body = [DetectedLanguage]),
+        (status = 400, description = "Invalid request", body = ErrorResponse),
+        (status = 403, description = "Invalid or missing API key", body = ErrorResponse),
+        (status = 429, description = "Rate limit exceeded", body = ErrorResponse)
+    )
+)]
 #[post("/detect")]
-async fn detect(req: HttpRequest, payload: web::Payload, args: web::Data<Arc<Args>>) -> Result<HttpResponse, ErrorResponse> {
+async fn detect(req: HttpRequest, payload: web::Payload, args: web::Data<Arc<Args>>, keys: web::Data<Option<Arc<ApiKeyStore>>>, limiter: web::Data<Arc<RateLimiter>>) -> Result<HttpResponse, ErrorResponse> {
+    let client = client_info(&req, &args);
     let body = parse_payload(req, payload).await?;
-    check_params(&body, &args, &[
-        ("q", &body.q)
-    ])?;
+    check_params(&body, &args, keys.as_deref(), &limiter, &client, &[])?;
 
-    let q = body.q.unwrap();
+    let q = single_text(body.q)?;
     let d = detect_lang(&q);
 
-    Ok(HttpResponse::Ok().json(serde_json::json!([{
-        "language": d.language.code,
-        "confidence": d.confidence
-    }])))
+    Ok(HttpResponse::Ok().json([DetectedLanguage {
+        language: d.language.code.to_string(),
+        confidence: d.confidence,
+    }]))
 }
 
 fn check_format(format: &str) -> Result<bool, ErrorResponse> {
@@ -210,158 +749,1073 @@ fn check_format(format: &str) -> Result<bool, ErrorResponse> {
         _ => Err(ErrorResponse {
             error: "Invalid format. Supported formats: text, html".to_string(),
             status: 400,
+            code: ErrorCode::UnsupportedFormat,
+            retry_after: None,
         })
     }
 }
 
+/// Picks the model for a request: the one asked by the client, then the
+/// first matching route, then the default model
+fn select_model(pool: &ModelPool, router: &Router, requested: Option<&str>, req: &RouteRequest) -> Result<Arc<LoadedModel>, ErrorResponse> {
+    if let Some(id) = requested.filter(|id| !id.is_empty()) {
+        return pool.get(id).ok_or_else(|| ErrorResponse {
+            error: format!("Model {} is not loaded", id),
+            status: 400,
+            code: ErrorCode::ModelNotLoaded,
+            retry_after: None,
+        });
+    }
+
+    router.resolve(req)
+        .and_then(|id| pool.get(id))
+        .or_else(|| pool.default_model())
+        .ok_or_else(|| ErrorResponse {
+            error: "No model loaded".to_string(),
+            status: 503,
+            code: ErrorCode::ModelNotLoaded,
+            retry_after: None,
+        })
+}
+
+static REQUEST_COUNTER: AtomicU64 = AtomicU64::new(1);
+
+// Seconds clients are asked to wait before retrying requests cut short by a shutdown
+const SHUTDOWN_RETRY_AFTER: u64 = 10;
+
+/// Uses the X-Request-Id header of the request if present, otherwise generates one
+fn request_id(req: &ServiceRequest) -> String {
+    req.headers().get("x-request-id")
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty() && v.len() <= 128)
+        .map(|v| v.to_string())
+        .unwrap_or_else(|| format!("{:08x}", REQUEST_COUNTER.fetch_add(1, Ordering::Relaxed)))
+}
+
+#[utoipa::path(
+    tag = "translate",
+    request_body(content(
+        (TranslateRequest = "application/json"),
+        (TranslateRequest = "application/x-www-form-urlencoded"),
+        (TranslateRequest = "multipart/form-data")
+    )),
+    responses(
+        (status = 200, description = "Translated text", body = TranslateResponse),
+        (status = 400, description = "Invalid request", body = ErrorResponse),
+        (status = 403, description = "Invalid or missing API key", body = ErrorResponse),
+        (status = 429, description = "Rate limit exceeded", body = ErrorResponse),
+        (status = 500, description = "The model failed to translate the text", body = ErrorResponse),
+        (status = 503, description = "Too many requests are waiting for the model, or the server is shutting down", body = ErrorResponse)
+    )
+)]
 #[post("/translate")]
-async fn translate(req: HttpRequest, payload: web::Payload, args: web::Data<Arc<Args>>, llm: actix_web::web::Data<Arc<llm::LLM>>) -> Result<HttpResponse, ErrorResponse> {
+async fn translate(req: HttpRequest, payload: web::Payload, args: web::Data<Arc<Args>>, keys: web::Data<Option<Arc<ApiKeyStore>>>, limiter: web::Data<Arc<RateLimiter>>, pool: web::Data<Arc<ModelPool>>, router: web::Data<Arc<Router>>, suggestions: web::Data<Option<Arc<SuggestionStore>>>) -> Result<HttpResponse, ErrorResponse> {
+    let client = client_info(&req, &args);
     let body = parse_payload(req, payload).await?;
-    check_params(&body, &args, &[
-        ("q", &body.q),
+    let client_id = check_params(&body, &args, keys.as_deref(), &limiter, &client, &[
         ("source", &body.source),
         ("target", &body.target),
     ])?;
 
-    let q = body.q.unwrap();
-    let source = body.source.unwrap();
-    let target = body.target.unwrap();
-    let format = body.format.unwrap_or("text".to_string());
+    let format = body.format.clone().unwrap_or("text".to_string());
     check_format(&format)?;
+
+    let texts = body.q.as_ref().unwrap().as_slice();
+    let chars = texts.iter().map(|q| q.chars().count()).sum();
+    // Batch calls wait for the interactive ones
+    let (batch, priority) = match body.q {
+        Some(OneOrMany::Many(_)) => (true, Priority::Batch),
+        _ => (false, Priority::Interactive)
+    };
+    let source = body.source.clone().unwrap();
+    let target = body.target.clone().unwrap();
+
+    let unsupported = |code: &String| ErrorResponse {
+        error: format!("{} is not supported", code),
+        status: 400,
+        code: ErrorCode::UnsupportedLanguage,
+        retry_after: None,
+    };
+
+    // Resolve the languages first, routing depends on them.
+    // All the texts of a batch are translated from the same language
+    let detected = (source == "auto").then(|| detect_lang(&texts.join("\n")));
+    let src_lang = match &detected {
+        Some(d) => d.language,
+        None => get_language_from_code(&source).ok_or_else(|| unsupported(&source))?
+    };
+    let tgt_lang = get_language_from_code(&target).ok_or_else(|| unsupported(&target))?;
+
+    let model = select_model(&pool, &router, body.model.as_deref(), &RouteRequest {
+        format: &format,
+        chars,
+        source: src_lang,
+        target: tgt_lang,
+    })?;
+    let llm = &model.llm;
+
+    METRICS.record_language_pair(src_lang.code, tgt_lang.code);
+
+    let span = tracing::info_span!("translate",
+        source = src_lang.code,
+        target = tgt_lang.code,
+        chars,
+        texts = texts.len(),
+        model = %model.id,
+        prompt_tokens = tracing::field::Empty,
+        generated_tokens = tracing::field::Empty,
+        queue_wait_ms = tracing::field::Empty,
+        decode_ms = tracing::field::Empty
+    );
+
+    if args.log_bodies {
+        for q in texts {
+            info!(parent: &span, text = %q, "translation request");
+        }
+    }
+
+    if detected.is_none() && !model.languages.supports(src_lang) {
+        return Err(unsupported(&source));
+    }
+    if !model.languages.supports(tgt_lang) {
+        return Err(unsupported(&target));
+    }
+
+    let sampling = model.sampling.with_overrides(&body.sampling_overrides()).map_err(|err| ErrorResponse {
+        error: format!("Invalid request: {}", err),
+        status: 400,
+        code: ErrorCode::InvalidRequest,
+        retry_after: None,
+    })?;
     
-    let mut pb = PromptBuilder::new();
+    let mut pb = PromptBuilder::new(llm.prompt_template());
     pb.set_format(&format);
+    pb.set_source_language(detected.is_none().then_some(src_lang));
+    pb.set_target_language(tgt_lang);
+
+    if let Some(glossary) = &body.glossary {
+        pb.set_glossary(glossary);
+    }
+    if let Some(context) = &body.context {
+        pb.set_context(context);
+    }
 
     // TODO: add HTML support
-    
-    if source == "auto"{
-        pb.set_source_language("auto");
-    }else{
-        let src_lang = get_language_from_code(&source).ok_or_else(|| ErrorResponse {
-            error: format!("{} is not supported", source),
-            status: 400,
-        })?;
-        pb.set_source_language(src_lang.name);
+
+    // Saved to --pending-jobs-file if the server shuts down before the translations are sent
+    let mut request = serde_json::to_value(&body).unwrap_or_default();
+    if let Some(fields) = request.as_object_mut() {
+        fields.remove("api_key");
     }
 
-    let tgt_lang = get_language_from_code(&target).ok_or_else(|| ErrorResponse {
-        error: format!("{} is not supported", target),
-        status: 400,
-    })?;
-    pb.set_target_language(tgt_lang.name);
+    let mut translations = Vec::with_capacity(texts.len());
+    let mut truncated = false;
+    let mut queue_position = None;
+    for q in texts {
+        let prompt = pb.build(q);
 
-    let llm = llm.get_ref();
-    let prompt = pb.build(&q);
-    
-    let translated_text = if source != target {
-        llm.run_prompt(prompt.system, prompt.user).unwrap_or(q.clone())
-    }else{
-        q.clone()
-    };
+        // Guard against runaway generation unless the caller asked for a specific limit
+        let mut sampling = sampling.clone();
+        if sampling.max_tokens.is_none() {
+            let input_tokens = llm.count_tokens(q).unwrap_or(q.len());
+            sampling.max_tokens = Some(output_token_budget(input_tokens, output_token_ratio(tgt_lang)).min(sampling.max_tokens_limit));
+        }
     
-    let mut response = serde_json::json!({"translatedText": improve_formatting(&q, &translated_text)});
+        let memory = suggestions.as_deref()
+            .filter(|_| args.translation_memory)
+            .and_then(|store| store.lookup(q, src_lang.code, tgt_lang.code));
 
-    // TODO: we just add this for compatibility for now
-    // we should allow multiple alternatives to be generated
-    if body.alternatives.is_some_and(|v| v > 0) {
-        response["alternatives"] = serde_json::json!([]);
-    }
+        let (translated_text, text_truncated) = if let Some(text) = memory {
+            info!(parent: &span, "translation memory hit");
+            (text, false)
+        } else if source != target {
+            let ticket = llm.enqueue(&client_id.to_string(), priority).map_err(|full| ErrorResponse {
+                error: "Too many requests are waiting, please retry later".to_string(),
+                status: 503,
+                code: ErrorCode::ModelBusy,
+                retry_after: Some(full.eta.as_secs().max(1)),
+            })?;
+            queue_position.get_or_insert((ticket.position, ticket.eta));
 
-    if source == "auto" {
-        let d = detect_lang(&q);
-        response["detectedLanguage"] = serde_json::json!({
-            "language": d.language.code,
-            "confidence": d.confidence
-        });
+            request["q"] = q.as_str().into();
+            let job = ticket.describe(serde_json::json!({ "model": model.id, "request": request }));
+
+            // Run inference on the blocking thread pool so that waiting in the queue doesn't block the workers
+            let loaded = model.clone();
+            let block_span = span.clone();
+            let result = web::block(move || block_span.in_scope(|| loaded.llm.run_prompt(ticket, prompt, &sampling)))
+                .await
+                .map_err(|err| ErrorResponse { error: err.to_string(), status: 500, code: ErrorCode::InferenceFailed, retry_after: None })?;
+
+            match result {
+                Ok(completion) => {
+                    // Nothing can interrupt the handler from here on
+                    job.delivered();
+                    let truncated = completion.truncated();
+                    (completion.text, truncated)
+                },
+                Err(err) => {
+                    METRICS.record_inference_failure(&model.id);
+                    error!(parent: &span, "Translation failed: {:#}", err);
+                    match args.on_inference_error {
+                        InferenceFallback::Source => (q.clone(), false),
+                        InferenceFallback::Error if err.is::<QueueClosed>() => return Err(ErrorResponse {
+                            error: QueueClosed.to_string(),
+                            status: 503,
+                            code: ErrorCode::ShuttingDown,
+                            retry_after: Some(SHUTDOWN_RETRY_AFTER),
+                        }),
+                        InferenceFallback::Error => return Err(ErrorResponse {
+                            error: "Translation failed".to_string(),
+                            status: 500,
+                            code: ErrorCode::InferenceFailed,
+                            retry_after: None,
+                        }),
+                    }
+                }
+            }
+        }else{
+            (q.clone(), false)
+        };
+    
+        let translated_text = improve_formatting(q, &translated_text);
+        if args.log_bodies {
+            info!(parent: &span, text = %translated_text, truncated = text_truncated, "translated");
+        } else {
+            info!(parent: &span, truncated = text_truncated, "translated");
+        }
+        truncated |= text_truncated;
+        translations.push(translated_text);
     }
 
-    Ok(HttpResponse::Ok().json(response))
+    let detected = detected.map(|d| DetectedLanguage {
+        language: d.language.code.to_string(),
+        confidence: d.confidence,
+    });
+    let response = TranslateResponse {
+        translated_text: if batch { OneOrMany::Many(translations) } else { OneOrMany::One(translations.remove(0)) },
+        model: model.id.clone(),
+        truncated,
+        // TODO: we just add this for compatibility for now
+        // we should allow multiple alternatives to be generated
+        alternatives: body.alternatives.is_some_and(|v| v > 0).then(Vec::new),
+        detected_language: detected.map(|d| if batch { OneOrMany::Many(vec![d; texts.len()]) } else { OneOrMany::One(d) }),
+    };
+
+    let mut res = HttpResponse::Ok();
+    if let Some((position, eta)) = queue_position {
+        // Lets callers back off when the server is busy
+        res.insert_header(("X-Queue-Position", position.to_string()));
+        res.insert_header(("X-Queue-ETA", eta.as_secs_f64().ceil().to_string()));
+    }
+    Ok(res.json(response))
 }
 
+#[utoipa::path(
+    tag = "translate",
+    responses(
+        (status = 501, description = "File translation is not supported yet", body = ErrorResponse)
+    )
+)]
 #[post("/translate_file")]
 async fn translate_file() -> Result<HttpResponse, ErrorResponse> {
     Err(ErrorResponse{
         error: "Not implemented".to_string(),
-        status: 501
+        status: 501,
+        code: ErrorCode::NotImplemented,
+        retry_after: None,
     })
 }
 
+#[utoipa::path(
+    tag = "feedback",
+    request_body(content(
+        (TranslateRequest = "application/json"),
+        (TranslateRequest = "application/x-www-form-urlencoded"),
+        (TranslateRequest = "multipart/form-data")
+    )),
+    responses(
+        (status = 200, description = "Suggestion saved", body = SuggestResponse),
+        (status = 400, description = "Invalid request", body = ErrorResponse),
+        (status = 403, description = "Suggestions are disabled or invalid API key", body = ErrorResponse)
+    )
+)]
 #[post("/suggest")]
-async fn suggest() -> Result<HttpResponse, ErrorResponse> {
-    Err(ErrorResponse{
-        error: "Not implemented".to_string(),
-        status: 501
-    })
+async fn suggest(req: HttpRequest, payload: web::Payload, args: web::Data<Arc<Args>>, keys: web::Data<Option<Arc<ApiKeyStore>>>, limiter: web::Data<Arc<RateLimiter>>, suggestions: web::Data<Option<Arc<SuggestionStore>>>) -> Result<HttpResponse, ErrorResponse> {
+    let store = suggestions.as_ref().as_ref().filter(|_| args.suggestions).ok_or_else(|| ErrorResponse {
+        error: "Suggestions are disabled on this server.".to_string(),
+        status: 403,
+        code: ErrorCode::FeatureDisabled,
+        retry_after: None,
+    })?;
+
+    let client = client_info(&req, &args);
+    let body = parse_payload(req, payload).await?;
+    let client_id = check_params(&body, &args, keys.as_deref(), &limiter, &client, &[
+        ("s", &body.s),
+        ("source", &body.source),
+        ("target", &body.target),
+    ])?;
+
+    // Anonymous users could fill the suggestions file
+    if !matches!(client_id, ClientId::Key(_)) {
+        return Err(ErrorResponse {
+            error: "A valid API key is required to suggest translations".to_string(),
+            status: 403,
+            code: ErrorCode::InvalidApiKey,
+            retry_after: None,
+        });
+    }
+
+    // Stored with the public codes, which the translation memory is looked up with (e.g. zh-Hans for zh)
+    let language = |code: &String| get_language_from_code(code).ok_or_else(|| ErrorResponse {
+        error: format!("{} is not supported", code),
+        status: 400,
+        code: ErrorCode::UnsupportedLanguage,
+        retry_after: None,
+    });
+    let source = language(body.source.as_ref().unwrap())?.code;
+    let target = language(body.target.as_ref().unwrap())?.code;
+
+    let store = store.clone();
+    let (q, s) = (single_text(body.q)?, body.s.unwrap());
+    web::block(move || store.add(&q, &s, source, target))
+        .await
+        .map_err(|err| ErrorResponse { error: err.to_string(), status: 500, code: ErrorCode::InternalError, retry_after: None })?
+        .map_err(|err| ErrorResponse { error: format!("{:#}", err), status: 500, code: ErrorCode::InternalError, retry_after: None })?;
+    Ok(HttpResponse::Ok().json(SuggestResponse { success: true }))
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+struct LanguagesQuery {
+    /// Model to list the languages of (default: the default model)
+    model: Option<String>
 }
 
+#[utoipa::path(
+    tag = "translate",
+    params(LanguagesQuery),
+    responses(
+        (status = 200, description = "Supported languages", body = [Language]),
+        (status = 400, description = "Model is not loaded", body = ErrorResponse)
+    )
+)]
 #[get("/languages")]
-async fn get_languages() -> impl Responder {
-    HttpResponse::Ok().json(&*LANGUAGES)
+async fn get_languages(query: web::Query<LanguagesQuery>, pool: web::Data<Arc<ModelPool>>) -> Result<HttpResponse, ErrorResponse> {
+    let model = match &query.model {
+        Some(id) => pool.get(id),
+        None => pool.default_model()
+    }.ok_or_else(|| ErrorResponse {
+        error: "Model is not loaded".to_string(),
+        status: 400,
+        code: ErrorCode::ModelNotLoaded,
+        retry_after: None,
+    })?;
+
+    Ok(HttpResponse::Ok().json(model.languages.all()))
+}
+
+#[derive(Debug, Deserialize)]
+struct AdminModelRequest {
+    model: String,
+    model_file: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct AdminModelsResponse {
+    /// Model used by requests that are not routed to another one
+    default: String,
+    loaded: Vec<AdminModel>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct AdminModel {
+    id: String,
+    repo: String,
+    file: String,
+    revision: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct AdminLoadResponse {
+    /// Id of the loaded model
+    loaded: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct AdminUnloadResponse {
+    /// Id of the unloaded model
+    unloaded: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct AdminRevokeResponse {
+    /// The revoked API key
+    revoked: String,
+}
+
+/// Admin key sent with `Authorization: Bearer <key>` or `X-Admin-Key: <key>`.
+/// Never read from the URL, which ends up in access logs and browser history.
+fn admin_key(req: &HttpRequest) -> Option<&str> {
+    let headers = req.headers();
+    headers.get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .or_else(|| headers.get("x-admin-key").and_then(|v| v.to_str().ok()))
+        .map(str::trim)
+}
+
+fn check_admin_key(args: &Args, req: &HttpRequest) -> Result<(), ErrorResponse> {
+    if args.admin_key.is_empty() {
+        return Err(ErrorResponse {
+            error: "Admin API is disabled".to_string(),
+            status: 403,
+            code: ErrorCode::FeatureDisabled,
+            retry_after: None,
+        });
+    }
+
+    if admin_key(req) != Some(args.admin_key.as_str()) {
+        return Err(ErrorResponse {
+            error: "Invalid admin key".to_string(),
+            status: 403,
+            code: ErrorCode::InvalidAdminKey,
+            retry_after: None,
+        });
+    }
+
+    Ok(())
+}
+
+#[get("/admin/models")]
+async fn admin_list_models(req: HttpRequest, args: web::Data<Arc<Args>>, pool: web::Data<Arc<ModelPool>>) -> Result<HttpResponse, ErrorResponse> {
+    check_admin_key(&args, &req)?;
+
+    Ok(HttpResponse::Ok().json(AdminModelsResponse {
+        default: pool.default_model_id().to_string(),
+        loaded: pool.loaded().iter().map(|m| AdminModel {
+            id: m.id.clone(),
+            repo: m.info.repo.clone(),
+            file: m.info.file.clone(),
+            revision: m.info.revision.clone(),
+        }).collect()
+    }))
+}
+
+#[post("/admin/models/load")]
+async fn admin_load_model(req: HttpRequest, body: web::Json<AdminModelRequest>, args: web::Data<Arc<Args>>, pool: web::Data<Arc<ModelPool>>) -> Result<HttpResponse, ErrorResponse> {
+    check_admin_key(&args, &req)?;
+
+    let body = body.into_inner();
+    let pool = pool.get_ref().clone();
+    let model_id = body.model.clone();
+
+    // Loading a model can take a while, don't block the server workers
+    web::block(move || pool.load(&body.model, body.model_file.as_deref(), None))
+        .await
+        .map_err(|err| ErrorResponse { error: err.to_string(), status: 500, code: ErrorCode::InternalError, retry_after: None })?
+        .map_err(|err| ErrorResponse { error: format!("{:#}", err), status: 400, code: ErrorCode::InvalidRequest, retry_after: None })?;
+
+    Ok(HttpResponse::Ok().json(AdminLoadResponse { loaded: model_id }))
+}
+
+#[post("/admin/models/unload")]
+async fn admin_unload_model(req: HttpRequest, body: web::Json<AdminModelRequest>, args: web::Data<Arc<Args>>, pool: web::Data<Arc<ModelPool>>) -> Result<HttpResponse, ErrorResponse> {
+    check_admin_key(&args, &req)?;
+
+    pool.unload(&body.model).map_err(|err| ErrorResponse {
+        error: err.to_string(),
+        status: 400,
+        code: ErrorCode::InvalidRequest,
+        retry_after: None,
+    })?;
+
+    Ok(HttpResponse::Ok().json(AdminUnloadResponse { unloaded: body.into_inner().model }))
+}
+
+#[utoipa::path(
+    tag = "monitoring",
+    responses(
+        (status = 200, description = "Metrics in the Prometheus text format", body = String, content_type = "text/plain")
+    )
+)]
+#[get("/metrics")]
+async fn get_metrics() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(METRICS.render())
+}
+
+/// Reported when the running job has held a model for longer than --stall-timeout
+fn stalled_response(running_for: Duration) -> HttpResponse {
+    HttpResponse::ServiceUnavailable().json(Unhealthy::Degraded(Degraded::Stalled { running_for: running_for.as_secs() }))
+}
+
+#[utoipa::path(
+    tag = "monitoring",
+    responses(
+        (status = 200, description = "The server is loading models or can translate", body = ServerStatus),
+        (status = 503, description = "Loading the models failed, or a translation is stalled", body = Unhealthy)
+    )
+)]
+#[get("/health")]
+async fn get_health(health: web::Data<Arc<Health>>, pool: web::Data<Arc<ModelPool>>) -> impl Responder {
+    let status = health.status();
+    let running_for = pool.longest_job();
+    if health.is_stalled(running_for) {
+        stalled_response(running_for.unwrap_or_default())
+    } else if health.is_alive() {
+        HttpResponse::Ok().json(status)
+    } else {
+        HttpResponse::ServiceUnavailable().json(Unhealthy::Status(status))
+    }
+}
+
+#[utoipa::path(
+    tag = "monitoring",
+    responses(
+        (status = 200, description = "The server can take translations", body = ServerStatus),
+        (status = 503, description = "The server is not ready, saturated or stalled", body = Unhealthy)
+    )
+)]
+#[get("/ready")]
+async fn get_ready(health: web::Data<Arc<Health>>, pool: web::Data<Arc<ModelPool>>) -> impl Responder {
+    let running_for = pool.longest_job();
+    if health.is_stalled(running_for) {
+        stalled_response(running_for.unwrap_or_default())
+    } else if health.is_ready() {
+        HttpResponse::Ok().json(ServerStatus::Ready)
+    } else if health.status() == ServerStatus::Ready {
+        HttpResponse::ServiceUnavailable().json(Unhealthy::Degraded(Degraded::Saturated { queue: METRICS.queue_depth() }))
+    } else {
+        HttpResponse::ServiceUnavailable().json(Unhealthy::Status(health.status()))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct AdminKeyRequest {
+    key: Option<String>,
+    #[serde(flatten)]
+    settings: ApiKeySettings
+}
+
+fn key_store(keys: &Option<Arc<ApiKeyStore>>) -> Result<&ApiKeyStore, ErrorResponse> {
+    keys.as_deref().ok_or_else(|| ErrorResponse {
+        error: "API key management is disabled (use --api-keys-file)".to_string(),
+        status: 400,
+        code: ErrorCode::FeatureDisabled,
+        retry_after: None,
+    })
+}
+
+fn required_key(body: &AdminKeyRequest) -> Result<&str, ErrorResponse> {
+    body.key.as_deref().ok_or_else(|| ErrorResponse {
+        error: "Invalid request: missing key parameter".to_string(),
+        status: 400,
+        code: ErrorCode::MissingParameter,
+        retry_after: None,
+    })
+}
+
+#[get("/admin/keys")]
+async fn admin_list_keys(req: HttpRequest, args: web::Data<Arc<Args>>, keys: web::Data<Option<Arc<ApiKeyStore>>>) -> Result<HttpResponse, ErrorResponse> {
+    check_admin_key(&args, &req)?;
+
+    Ok(HttpResponse::Ok().json(key_store(&keys)?.list()))
+}
+
+#[post("/admin/keys")]
+async fn admin_create_key(req: HttpRequest, body: web::Json<AdminKeyRequest>, args: web::Data<Arc<Args>>, keys: web::Data<Option<Arc<ApiKeyStore>>>) -> Result<HttpResponse, ErrorResponse> {
+    check_admin_key(&args, &req)?;
+
+    let key = key_store(&keys)?.create(body.into_inner().settings)
+        .map_err(|err| ErrorResponse { error: format!("{:#}", err), status: 500, code: ErrorCode::InternalError, retry_after: None })?;
+    Ok(HttpResponse::Ok().json(key))
+}
+
+#[post("/admin/keys/update")]
+async fn admin_update_key(req: HttpRequest, body: web::Json<AdminKeyRequest>, args: web::Data<Arc<Args>>, keys: web::Data<Option<Arc<ApiKeyStore>>>) -> Result<HttpResponse, ErrorResponse> {
+    check_admin_key(&args, &req)?;
+
+    let key = required_key(&body)?.to_string();
+    let key = key_store(&keys)?.update(&key, body.into_inner().settings)
+        .map_err(|err| ErrorResponse { error: format!("{:#}", err), status: 400, code: ErrorCode::InvalidRequest, retry_after: None })?;
+    Ok(HttpResponse::Ok().json(key))
+}
+
+#[post("/admin/keys/revoke")]
+async fn admin_revoke_key(req: HttpRequest, body: web::Json<AdminKeyRequest>, args: web::Data<Arc<Args>>, keys: web::Data<Option<Arc<ApiKeyStore>>>) -> Result<HttpResponse, ErrorResponse> {
+    check_admin_key(&args, &req)?;
+
+    let key = required_key(&body)?;
+    key_store(&keys)?.revoke(key)
+        .map_err(|err| ErrorResponse { error: format!("{:#}", err), status: 400, code: ErrorCode::InvalidRequest, retry_after: None })?;
+    Ok(HttpResponse::Ok().json(AdminRevokeResponse { revoked: key.to_string() }))
+}
+
+fn suggestion_store(suggestions: &Option<Arc<SuggestionStore>>) -> Result<&SuggestionStore, ErrorResponse> {
+    suggestions.as_deref().ok_or_else(|| ErrorResponse {
+        error: "Suggestions are disabled (use --suggestions)".to_string(),
+        status: 400,
+        code: ErrorCode::FeatureDisabled,
+        retry_after: None,
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct AdminSuggestionsQuery {
+    #[serde(default)]
+    format: ExportFormat,
+    /// Only export approved suggestions
+    #[serde(default)]
+    approved: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct AdminSuggestionRequest {
+    id: u64,
+}
+
+#[get("/admin/suggestions")]
+async fn admin_list_suggestions(req: HttpRequest, args: web::Data<Arc<Args>>, suggestions: web::Data<Option<Arc<SuggestionStore>>>) -> Result<HttpResponse, ErrorResponse> {
+    check_admin_key(&args, &req)?;
+
+    Ok(HttpResponse::Ok().json(suggestion_store(&suggestions)?.list()))
+}
+
+#[get("/admin/suggestions/export")]
+async fn admin_export_suggestions(req: HttpRequest, query: web::Query<AdminSuggestionsQuery>, args: web::Data<Arc<Args>>, suggestions: web::Data<Option<Arc<SuggestionStore>>>) -> Result<HttpResponse, ErrorResponse> {
+    check_admin_key(&args, &req)?;
+
+    let data = suggestion_store(&suggestions)?.export(query.format, query.approved);
+    Ok(HttpResponse::Ok().content_type(query.format.content_type()).body(data))
+}
+
+#[post("/admin/suggestions/approve")]
+async fn admin_approve_suggestion(req: HttpRequest, body: web::Json<AdminSuggestionRequest>, args: web::Data<Arc<Args>>, suggestions: web::Data<Option<Arc<SuggestionStore>>>) -> Result<HttpResponse, ErrorResponse> {
+    check_admin_key(&args, &req)?;
+
+    let suggestion = suggestion_store(&suggestions)?.approve(body.id)
+        .map_err(|err| ErrorResponse { error: format!("{:#}", err), status: 400, code: ErrorCode::InvalidRequest, retry_after: None })?;
+    Ok(HttpResponse::Ok().json(suggestion))
+}
+
+#[post("/admin/suggestions/delete")]
+async fn admin_delete_suggestion(req: HttpRequest, body: web::Json<AdminSuggestionRequest>, args: web::Data<Arc<Args>>, suggestions: web::Data<Option<Arc<SuggestionStore>>>) -> Result<HttpResponse, ErrorResponse> {
+    check_admin_key(&args, &req)?;
+
+    suggestion_store(&suggestions)?.delete(body.id)
+        .map_err(|err| ErrorResponse { error: format!("{:#}", err), status: 400, code: ErrorCode::InvalidRequest, retry_after: None })?;
+    Ok(HttpResponse::Ok().json(SuggestResponse { success: true }))
 }
 
+#[utoipa::path(
+    tag = "frontend",
+    responses((status = 200, description = "Settings of the web frontend", body = FrontendSettings))
+)]
 #[get("/frontend/settings")]
-async fn get_frontend_settings(args: web::Data<Arc<Args>>) -> impl Responder {
-    HttpResponse::Ok().json(serde_json::json!({
-        "apiKeys": false,
-        "charLimit": args.char_limit,
-        "filesTranslation": false,
-        "frontendTimeout": 1000,
-        "keyRequired": false,
-        "language": {
-            "source": {
-                "code": "auto",
-                "name": "Auto Detect"
-            },
-            "target": {
-                "code": "en",
-                "name": "English"
-            }
+async fn get_frontend_settings(args: web::Data<Arc<Args>>, keys: web::Data<Option<Arc<ApiKeyStore>>>) -> impl Responder {
+    HttpResponse::Ok().json(FrontendSettings {
+        api_keys: keys.is_some(),
+        char_limit: args.char_limit,
+        files_translation: false,
+        frontend_timeout: 1000,
+        key_required: !args.api_key.is_empty() || args.require_api_key || !args.require_api_key_origin.is_empty(),
+        req_limit: args.req_limit,
+        char_rate_limit: args.char_rate_limit,
+        language: FrontendLanguages {
+            source: FrontendLanguage { code: "auto", name: "Auto Detect" },
+            target: FrontendLanguage { code: "en", name: "English" },
         },
-        "suggestions": false,
-        "supportedFilesFormat": []
-    }))
+        suggestions: args.suggestions,
+        supported_files_format: Vec::new(),
+    })
+}
+
+/// Appends the translations that could not complete before shutting down to `path`
+fn save_pending_jobs(path: &std::path::Path, jobs: &[serde_json::Value]) -> anyhow::Result<()> {
+    use std::io::Write;
+
+    let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+    for job in jobs {
+        writeln!(file, "{}", job)?;
+    }
+    Ok(())
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let args = Arc::new(Args::parse());
+    let (cmd, from_config) = config::command(Args::command()).unwrap_or_else(|err| {
+        eprintln!("{:#}", err);
+        std::process::exit(1);
+    });
+    let matches = cmd.clone().get_matches();
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
 
-    let host = args.host.clone();
-    let port = args.port;
+    if let Some(Command::Config { action: ConfigCommand::Print }) = &args.command {
+        config::print(&Args::command(), &matches, &from_config);
+        return Ok(());
+    }
 
-    let model_path = load_model(&args.model, &args.model_file).unwrap_or_else(|err| {
-        eprintln!("Failed to load model: {}", err);
+    if let Some(Command::Langdetect { action: LangdetectCommand::Benchmark { file } }) = &args.command {
+        let test_set = match file {
+            Some(path) => std::fs::read_to_string(path).unwrap_or_else(|err| {
+                eprintln!("Unable to read test set {}: {}", path, err);
+                std::process::exit(1);
+            }),
+            None => include_str!("../lexilang/benchmark.tsv").to_string(),
+        };
+        if let Err(err) = print_detection_benchmark(&test_set) {
+            eprintln!("{:#}", err);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let models_file = (!args.models_file.is_empty()).then(|| std::path::Path::new(&args.models_file));
+    let registry = ModelRegistry::load(models_file).unwrap_or_else(|err| {
+        eprintln!("Failed to load model registry: {:#}", err);
         std::process::exit(1);
     });
-    
-    println!("Loading model: {}", model_path.display());
 
-    let llm = Arc::new(llm::LLM::new(model_path, args.cpu, args.verbose).unwrap_or_else(|err| {
-        eprintln!("Failed to initialize LLM: {}", err);
+    if let Some(Command::Models { action: ModelsCommand::List }) = &args.command {
+        registry.print();
+        return Ok(());
+    }
+
+    let keys = (!args.api_keys_file.is_empty()).then(|| {
+        ApiKeyStore::load(std::path::Path::new(&args.api_keys_file)).unwrap_or_else(|err| {
+            eprintln!("Failed to load API keys: {:#}", err);
+            std::process::exit(1);
+        })
+    }).map(Arc::new);
+
+    if let Some(Command::Keys { action }) = &args.command {
+        let Some(keys) = &keys else {
+            eprintln!("--api-keys-file is required to manage API keys");
+            std::process::exit(1);
+        };
+
+        let result = match action {
+            KeysCommand::Add { settings } => keys.create(settings.clone()).map(|key| println!("{}", key.key)),
+            KeysCommand::List => {
+                keys.print();
+                Ok(())
+            },
+            KeysCommand::Revoke { key } => keys.revoke(key),
+            KeysCommand::Enable { key } | KeysCommand::Disable { key } => {
+                let enabled = matches!(action, KeysCommand::Enable { .. });
+                keys.update(key, ApiKeySettings { enabled: Some(enabled), ..Default::default() }).map(|_| ())
+            }
+        };
+
+        if let Err(err) = result {
+            eprintln!("{:#}", err);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let suggestions = (args.suggestions || args.translation_memory || matches!(args.command, Some(Command::Suggestions { .. }))).then(|| {
+        SuggestionStore::load(std::path::Path::new(&args.suggestions_file)).unwrap_or_else(|err| {
+            eprintln!("Failed to load suggestions: {:#}", err);
+            std::process::exit(1);
+        })
+    }).map(Arc::new);
+
+    if let (Some(Command::Suggestions { action }), Some(suggestions)) = (&args.command, &suggestions) {
+        let result = match action {
+            SuggestionsCommand::List => {
+                suggestions.print();
+                Ok(())
+            },
+            SuggestionsCommand::Export { format, approved } => {
+                print!("{}", suggestions.export(*format, *approved));
+                Ok(())
+            },
+            SuggestionsCommand::Approve { id } => suggestions.approve(*id).map(|_| ()),
+            SuggestionsCommand::Delete { id } => suggestions.delete(*id),
+        };
+
+        if let Err(err) = result {
+            eprintln!("{:#}", err);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    logging::init(args.log_format, args.verbose);
+
+    // Sampling options set by the user take precedence over the models' recommendations
+    let explicit_sampling: HashSet<String> = cmd
+        .get_arguments()
+        .map(|arg| arg.get_id().to_string())
+        .filter(|id| match matches.value_source(id) {
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable) => true,
+            Some(ValueSource::DefaultValue) => from_config.contains(id),
+            _ => false,
+        })
+        .collect();
+
+    if let Err(err) = args.sampling.validate() {
+        eprintln!("Invalid sampling configuration: {}", err);
+        std::process::exit(1);
+    }
+
+    if let Err(err) = args.llm.validate() {
+        eprintln!("Invalid configuration: {}", err);
+        std::process::exit(1);
+    }
+
+    if let Err(err) = args.validate() {
+        eprintln!("Invalid configuration: {:#}", err);
+        std::process::exit(1);
+    }
+
+    let mut router = Router::new(args.routes.clone());
+    if !args.routes_file.is_empty() {
+        if let Err(err) = router.load_table(std::path::Path::new(&args.routes_file)) {
+            eprintln!("Failed to load routing table: {:#}", err);
+            std::process::exit(1);
+        }
+    }
+
+    for id in router.models() {
+        if !args.model.contains(id) {
+            eprintln!("Invalid route: model {} is not loaded (use -m {},{})", id, args.model.join(","), id);
+            std::process::exit(1);
+        }
+    }
+
+    let host = args.host.clone();
+    let port = args.port;
+
+    let download_opts = DownloadOptions {
+        offline: args.offline,
+        cache_dir: (!args.cache_dir.is_empty()).then(|| args.cache_dir.clone().into()),
+        endpoint: (!args.hf_endpoint.is_empty()).then(|| args.hf_endpoint.clone()),
+        verify: !args.no_verify,
+    };
+
+    send_logs_to_tracing(LogOptions::default().with_logs_enabled(args.verbose));
+
+    let backend = Arc::new(LlamaBackend::init().unwrap_or_else(|err| {
+        eprintln!("Failed to initialize llama backend: {}", err);
         std::process::exit(1);
     }));
 
+    // Tells whether the backend was freed at shutdown
+    let backend_ref = Arc::downgrade(&backend);
+
+    let default_model = args.model[0].clone();
+    let pool = Arc::new(ModelPool::new(
+        backend,
+        registry,
+        download_opts,
+        args.sampling.clone(),
+        explicit_sampling,
+        args.llm.clone(),
+        default_model.clone(),
+    ));
+
+    let router = Arc::new(router);
+    let args = Arc::new(args);
+    let health = Arc::new(Health::new(args.ready_queue_limit, (args.stall_timeout > 0).then(|| Duration::from_secs(args.stall_timeout))));
+    let limiter = Arc::new(RateLimiter::new(Limits {
+        requests: (args.req_limit > 0).then_some(args.req_limit),
+        chars: (args.char_rate_limit > 0).then_some(args.char_rate_limit),
+    }));
+
+    let tls = (!args.tls_cert.is_empty()).then(|| {
+        CertResolver::load(std::path::Path::new(&args.tls_cert), std::path::Path::new(&args.tls_key))
+            .and_then(|resolver| Ok((resolver.server_config()?, resolver)))
+            .unwrap_or_else(|err| {
+                eprintln!("{:#}", err);
+                std::process::exit(1);
+            })
+    });
+
     print_banner();
+    args.llm.print();
 
-    let server = HttpServer::new(move || {
-        let generated = generate();
-
-        App::new()
-            // .service(index)
-            .app_data(web::Data::new(llm.clone()))
-            .app_data(web::Data::new(args.clone()))
-            .service(get_languages)
-            .service(get_frontend_settings)
-            .service(translate)
-            .service(translate_file)
-            .service(detect)
-            .service(suggest)
-            .service(ResourceFiles::new("/", generated))
-    })
-    .bind((host.clone(), port))?
-    .run();
+    let mut addrs = vec![format!("{}:{}", host, port)];
+    addrs.extend(args.bind.iter().cloned());
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    let unix_socket = args.unix_socket.clone();
+
+    let server = {
+        let pool = pool.clone();
+        let args = args.clone();
+        let health = health.clone();
 
-    println!("Running on: http://{}:{}", host, port);
+        let mut server = HttpServer::new(move || {
+            let generated = generate();
+
+            App::new()
+                // .service(index)
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(router.clone()))
+                .app_data(web::Data::new(args.clone()))
+                .app_data(web::Data::new(health.clone()))
+                .app_data(web::Data::new(keys.clone()))
+                .app_data(web::Data::new(limiter.clone()))
+                .app_data(web::Data::new(suggestions.clone()))
+                .wrap_fn(|req, srv| {
+                    let endpoint = req.match_pattern().unwrap_or_else(|| "other".to_string());
+                    let request_id = request_id(&req);
+                    let span = tracing::info_span!("request", id = %request_id, method = %req.method(), path = %req.path());
+                    let start = Instant::now();
+                    let res = span.in_scope(|| srv.call(req));
+                    async move {
+                        let mut res = res.await?;
+                        let status = res.status().as_u16();
+                        METRICS.record_request(&endpoint, status);
+                        info!(status, elapsed_ms = start.elapsed().as_millis() as u64, "request completed");
+
+                        if let Ok(value) = HeaderValue::from_str(&request_id) {
+                            res.headers_mut().insert(HeaderName::from_static("x-request-id"), value);
+                        }
+                        Ok(res)
+                    }.instrument(span)
+                })
+                .wrap(args.cors.middleware(keys.clone()))
+                .service(get_languages)
+                .service(get_frontend_settings)
+                .service(translate)
+                .service(translate_file)
+                .service(detect)
+                .service(suggest)
+                .service(admin_list_models)
+                .service(admin_load_model)
+                .service(admin_unload_model)
+                .service(admin_list_keys)
+                .service(admin_create_key)
+                .service(admin_update_key)
+                .service(admin_revoke_key)
+                .service(admin_list_suggestions)
+                .service(admin_export_suggestions)
+                .service(admin_approve_suggestion)
+                .service(admin_delete_suggestion)
+                .service(get_metrics)
+                .service(get_health)
+                .service(get_ready)
+                .service(web::redirect("/docs", "/docs/"))
+                .service(SwaggerUi::new("/docs/{_:.*}").url("/spec", openapi::ApiDoc::openapi()))
+                .service(ResourceFiles::new("/", generated))
+        })
+        // Shutdown signals are handled by `stop_on_signals`, which drains the queues first
+        .disable_signals()
+        .shutdown_timeout(SHUTDOWN_GRACE.as_secs());
+
+        for addr in &addrs {
+            server = match &tls {
+                Some((config, _)) => server.bind_rustls_0_23(addr.as_str(), config.clone())?,
+                None => server.bind(addr.as_str())?,
+            };
+        }
+
+        #[cfg(unix)]
+        if !unix_socket.is_empty() {
+            use std::os::unix::fs::FileTypeExt;
+
+            // Remove the socket left over by a previous run, but never another kind of file
+            match std::fs::symlink_metadata(&unix_socket) {
+                Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(&unix_socket)?,
+                Ok(_) => return Err(std::io::Error::new(
+                    std::io::ErrorKind::AlreadyExists,
+                    format!("{} already exists and is not a socket", unix_socket)
+                )),
+                Err(_) => {}
+            }
+            server = server.bind_uds(&unix_socket)?;
+        }
 
-    return server.await;
+        server.run()
+    };
+    stop_on_signals(server.handle(), &pool, health.clone(), Duration::from_secs(args.shutdown_timeout));
+
+    #[cfg(unix)]
+    if let Some((_, resolver)) = &tls {
+        tls::reload_on_sighup(resolver.clone());
+    }
+
+    for addr in &addrs {
+        println!("Running on: {}://{} (loading models)", scheme, addr);
+    }
+    if !unix_socket.is_empty() {
+        println!("Running on: unix:{} (loading models)", unix_socket);
+    }
+
+    // Bind first so that orchestrators can see that we are loading
+    {
+        let pool = pool.clone();
+        let args = args.clone();
+        std::thread::spawn(move || {
+            for id in &args.model {
+                if health.status() == ServerStatus::ShuttingDown {
+                    return;
+                }
+
+                let is_default = *id == default_model;
+                let model_file = (is_default && !args.model_file.is_empty()).then_some(args.model_file.as_str());
+                let prompt_template = (is_default && !args.prompt_template.is_empty())
+                    .then(|| std::path::Path::new(&args.prompt_template));
+
+                if let Err(err) = pool.load(id, model_file, prompt_template) {
+                    error!("Failed to load model {}: {:#}", id, err);
+                    // Keep serving /health so that orchestrators can see why
+                    health.set_status(ServerStatus::Failed(format!("{:#}", err)));
+                    return;
+                }
+            }
+
+            health.set_status(ServerStatus::WarmingUp);
+            let warm_up = pool.default_model()
+                .ok_or_else(|| anyhow::anyhow!("Default model is not loaded"))
+                .and_then(|model| model.warm_up());
+
+            match warm_up {
+                Ok(()) => {
+                    health.set_status(ServerStatus::Ready);
+                    info!("Ready");
+                },
+                Err(err) => {
+                    error!("Warm-up translation failed: {:#}", err);
+                    health.set_status(ServerStatus::Failed(format!("{:#}", err)));
+                }
+            }
+        });
+    }
+
+    let result = server.await;
+
+    // The queues were drained by `stop_on_signals`, unless the server stopped for another reason
+    pool.close(SHUTDOWN_GRACE);
+    let pending = pool.unfinished();
+
+    if !pending.is_empty() {
+        if args.pending_jobs_file.is_empty() {
+            warn!("{} pending translations were dropped (see --pending-jobs-file)", pending.len());
+        } else {
+            match save_pending_jobs(std::path::Path::new(&args.pending_jobs_file), &pending) {
+                Ok(()) => info!("Saved {} pending translations to {}", pending.len(), args.pending_jobs_file),
+                Err(err) => error!("Failed to save pending translations: {:#}", err),
+            }
+        }
+    }
+
+    // Free the models and the llama backend before exiting. Blocking tasks release their models
+    // right after the queues, but a model being loaded keeps the backend until it is done.
+    drop(pool);
+    let deadline = Instant::now() + SHUTDOWN_GRACE;
+    while backend_ref.strong_count() > 0 && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    if backend_ref.strong_count() > 0 {
+        warn!("The llama backend is still in use (e.g. by a model being loaded), it is freed when the process exits");
+    } else {
+        info!("Freed the models and the llama backend");
+    }
+    info!("Shutdown complete");
+    result
 }
\ No newline at end of file
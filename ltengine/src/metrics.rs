@@ -0,0 +1,188 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::Duration;
+use once_cell::sync::Lazy;
+
+/// Process-wide metrics, exported in the Prometheus text format at `/metrics`
+pub static METRICS: Lazy<Metrics> = Lazy::new(Metrics::default);
+
+const WAIT_BUCKETS: [f64; 11] = [0.005, 0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+const TOKENS_PER_SECOND_BUCKETS: [f64; 9] = [1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0];
+
+struct Histogram {
+    buckets: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Histogram {
+        Histogram { buckets, counts: vec![0; buckets.len()], sum: 0.0, count: 0 }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (bound, count) in self.buckets.iter().zip(self.counts.iter_mut()) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str) {
+        for (bound, count) in self.buckets.iter().zip(&self.counts) {
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count);
+        let _ = writeln!(out, "{}_sum {}", name, self.sum);
+        let _ = writeln!(out, "{}_count {}", name, self.count);
+    }
+}
+
+pub struct Metrics {
+    requests: Mutex<BTreeMap<(String, u16), u64>>,
+    language_pairs: Mutex<BTreeMap<(String, String), u64>>,
+    queue_depth: AtomicI64,
+    queue_wait: Mutex<Histogram>,
+    prompt_tokens: AtomicU64,
+    generated_tokens: AtomicU64,
+    tokens_per_second: Mutex<Histogram>,
+    prompt_cache_hits: AtomicU64,
+    prompt_cache_misses: AtomicU64,
+    inference_failures: Mutex<BTreeMap<String, u64>>,
+    inference_retries: AtomicU64,
+    model_load_seconds: Mutex<BTreeMap<String, f64>>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            requests: Mutex::new(BTreeMap::new()),
+            language_pairs: Mutex::new(BTreeMap::new()),
+            queue_depth: AtomicI64::new(0),
+            queue_wait: Mutex::new(Histogram::new(&WAIT_BUCKETS)),
+            prompt_tokens: AtomicU64::new(0),
+            generated_tokens: AtomicU64::new(0),
+            tokens_per_second: Mutex::new(Histogram::new(&TOKENS_PER_SECOND_BUCKETS)),
+            prompt_cache_hits: AtomicU64::new(0),
+            prompt_cache_misses: AtomicU64::new(0),
+            inference_failures: Mutex::new(BTreeMap::new()),
+            inference_retries: AtomicU64::new(0),
+            model_load_seconds: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn record_request(&self, endpoint: &str, status: u16) {
+        *self.requests.lock().unwrap().entry((endpoint.to_string(), status)).or_insert(0) += 1;
+    }
+
+    pub fn record_language_pair(&self, source: &str, target: &str) {
+        *self.language_pairs.lock().unwrap().entry((source.to_string(), target.to_string())).or_insert(0) += 1;
+    }
+
+    /// A request started waiting for the LLM
+    pub fn enter_queue(&self) {
+        self.queue_depth.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A request stopped waiting for the LLM after `wait`
+    pub fn leave_queue(&self, wait: Duration) {
+        self.queue_depth.fetch_sub(1, Ordering::Relaxed);
+        self.queue_wait.lock().unwrap().observe(wait.as_secs_f64());
+    }
+
+    pub fn queue_depth(&self) -> i64 {
+        self.queue_depth.load(Ordering::Relaxed)
+    }
+
+    pub fn record_generation(&self, prompt_tokens: usize, generated_tokens: usize, elapsed: Duration) {
+        self.prompt_tokens.fetch_add(prompt_tokens as u64, Ordering::Relaxed);
+        self.generated_tokens.fetch_add(generated_tokens as u64, Ordering::Relaxed);
+        if generated_tokens > 0 && !elapsed.is_zero() {
+            self.tokens_per_second.lock().unwrap().observe(generated_tokens as f64 / elapsed.as_secs_f64());
+        }
+    }
+
+    pub fn record_prompt_cache(&self, hit: bool) {
+        let counter = if hit { &self.prompt_cache_hits } else { &self.prompt_cache_misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A translation failed, after the retry if any
+    pub fn record_inference_failure(&self, model: &str) {
+        *self.inference_failures.lock().unwrap().entry(model.to_string()).or_insert(0) += 1;
+    }
+
+    pub fn record_inference_retry(&self) {
+        self.inference_retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_model_load(&self, model: &str, elapsed: Duration) {
+        self.model_load_seconds.lock().unwrap().insert(model.to_string(), elapsed.as_secs_f64());
+    }
+
+    /// Renders all the metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let header = |out: &mut String, name: &str, kind: &str, help: &str| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        };
+
+        header(&mut out, "ltengine_requests_total", "counter", "HTTP requests by endpoint and status");
+        for ((endpoint, status), count) in self.requests.lock().unwrap().iter() {
+            let _ = writeln!(out, "ltengine_requests_total{{endpoint=\"{}\",status=\"{}\"}} {}", escape(endpoint), status, count);
+        }
+
+        header(&mut out, "ltengine_translations_total", "counter", "Translation requests by language pair");
+        for ((source, target), count) in self.language_pairs.lock().unwrap().iter() {
+            let _ = writeln!(out, "ltengine_translations_total{{source=\"{}\",target=\"{}\"}} {}", escape(source), escape(target), count);
+        }
+
+        header(&mut out, "ltengine_queue_depth", "gauge", "Requests waiting for the LLM");
+        let _ = writeln!(out, "ltengine_queue_depth {}", self.queue_depth());
+
+        header(&mut out, "ltengine_queue_wait_seconds", "histogram", "Time spent waiting for the LLM");
+        self.queue_wait.lock().unwrap().render(&mut out, "ltengine_queue_wait_seconds");
+
+        header(&mut out, "ltengine_prompt_tokens_total", "counter", "Prompt tokens processed");
+        let _ = writeln!(out, "ltengine_prompt_tokens_total {}", self.prompt_tokens.load(Ordering::Relaxed));
+
+        header(&mut out, "ltengine_generated_tokens_total", "counter", "Tokens generated");
+        let _ = writeln!(out, "ltengine_generated_tokens_total {}", self.generated_tokens.load(Ordering::Relaxed));
+
+        header(&mut out, "ltengine_tokens_per_second", "histogram", "Generation speed of each request");
+        self.tokens_per_second.lock().unwrap().render(&mut out, "ltengine_tokens_per_second");
+
+        header(&mut out, "ltengine_prompt_cache_hits_total", "counter", "Requests that reused a cached system prompt");
+        let _ = writeln!(out, "ltengine_prompt_cache_hits_total {}", self.prompt_cache_hits.load(Ordering::Relaxed));
+
+        header(&mut out, "ltengine_prompt_cache_misses_total", "counter", "Requests that had to evaluate the system prompt");
+        let _ = writeln!(out, "ltengine_prompt_cache_misses_total {}", self.prompt_cache_misses.load(Ordering::Relaxed));
+
+        header(&mut out, "ltengine_inference_failures_total", "counter", "Translations that failed by model");
+        for (model, count) in self.inference_failures.lock().unwrap().iter() {
+            let _ = writeln!(out, "ltengine_inference_failures_total{{model=\"{}\"}} {}", escape(model), count);
+        }
+
+        header(&mut out, "ltengine_inference_retries_total", "counter", "Failed generations retried on a fresh context");
+        let _ = writeln!(out, "ltengine_inference_retries_total {}", self.inference_retries.load(Ordering::Relaxed));
+
+        header(&mut out, "ltengine_model_load_seconds", "gauge", "Time it took to load each model");
+        for (model, seconds) in self.model_load_seconds.lock().unwrap().iter() {
+            let _ = writeln!(out, "ltengine_model_load_seconds{{model=\"{}\"}} {}", escape(model), seconds);
+        }
+
+        out
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
@@ -1,12 +1,131 @@
-use std::collections::HashMap;
-use std::path::PathBuf;
-use hf_hub::api::sync::ApiBuilder;
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+use hf_hub::{Cache, Repo, RepoType};
+use hf_hub::api::sync::{ApiBuilder, ApiError};
 use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+use crate::prompt::{PromptStyle, PromptTemplate};
+use crate::sampling::RecommendedSampling;
+
+// Registry shipped with the binary
+const DEFAULT_REGISTRY: &str = include_str!("../models.toml");
 
 #[derive(Clone, Debug)]
 pub struct HuggingFace {
-    pub repo: &'static str,
-    pub model: &'static str,
+    pub repo: String,
+    pub model: String,
+    pub revision: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModelInfo {
+    pub repo: String,
+    pub file: String,
+    #[serde(default = "default_revision")]
+    pub revision: String,
+    pub sha256: Option<String>,
+    pub quantization: Option<String>,
+    pub context_length: Option<u32>,
+    #[serde(default)]
+    pub prompt_style: PromptStyle,
+    pub prompt_template: Option<PathBuf>,
+
+    /// Language codes supported by the model (None = all languages)
+    pub languages: Option<Vec<String>>,
+    #[serde(default)]
+    pub sampling: RecommendedSampling,
+}
+
+fn default_revision() -> String {
+    "main".to_string()
+}
+
+impl ModelInfo {
+    pub fn hf(&self) -> HuggingFace {
+        HuggingFace {
+            repo: self.repo.clone(),
+            model: self.file.clone(),
+            revision: self.revision.clone(),
+        }
+    }
+
+    pub fn prompt_template(&self) -> Result<PromptTemplate> {
+        match &self.prompt_template {
+            Some(path) => PromptTemplate::load(path),
+            None => Ok(PromptTemplate::builtin(self.prompt_style))
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RegistryFile {
+    #[serde(default)]
+    models: BTreeMap<String, ModelInfo>,
+}
+
+#[derive(Debug)]
+pub struct ModelRegistry {
+    models: BTreeMap<String, ModelInfo>,
+}
+
+impl ModelRegistry {
+    /// Loads the built-in registry, with the entries of `overrides` added
+    /// or replacing the built-in ones.
+    pub fn load(overrides: Option<&Path>) -> Result<ModelRegistry> {
+        let mut models = toml::from_str::<RegistryFile>(DEFAULT_REGISTRY)
+            .with_context(|| "Invalid built-in model registry")?
+            .models;
+
+        if let Some(path) = overrides {
+            let data = std::fs::read_to_string(path)
+                .with_context(|| format!("Unable to read models file: {}", path.display()))?;
+            let file: RegistryFile = toml::from_str(&data)
+                .with_context(|| format!("Invalid models file: {}", path.display()))?;
+            models.extend(file.models);
+        }
+
+        Ok(ModelRegistry { models })
+    }
+
+    pub fn get(&self, id: &str) -> Option<&ModelInfo> {
+        self.models.get(id)
+    }
+
+    pub fn print(&self) {
+        println!("{:<16} {:<10} {:<9} {:<8} SOURCE", "ID", "QUANT", "CONTEXT", "PROMPT");
+        for (id, info) in &self.models {
+            let prompt = match &info.prompt_template {
+                Some(_) => "custom",
+                None => info.prompt_style.name()
+            };
+            println!("{:<16} {:<10} {:<9} {:<8} {}/{}@{}",
+                id,
+                info.quantization.as_deref().unwrap_or("-"),
+                info.context_length.map_or("-".to_string(), |c| c.to_string()),
+                prompt,
+                info.repo, info.file, info.revision
+            );
+        }
+    }
+}
+
+/// Where and how remote models are fetched
+#[derive(Clone, Debug, Default)]
+pub struct DownloadOptions {
+    /// Only use models that are already in the local cache
+    pub offline: bool,
+    /// Hugging Face hub cache directory (defaults to $HF_HOME/hub)
+    pub cache_dir: Option<PathBuf>,
+    /// Hugging Face endpoint or mirror (defaults to $HF_ENDPOINT)
+    pub endpoint: Option<String>,
+    /// Verify the SHA256 of downloaded models against the registry
+    pub verify: bool,
 }
 
 #[derive(Debug)]
@@ -16,21 +135,12 @@ pub enum Model {
     },
     Remote {
         hf: HuggingFace,
+        sha256: Option<String>,
     },
 }
 
-pub static MODELS: once_cell::sync::Lazy<HashMap<&'static str, HuggingFace>> = once_cell::sync::Lazy::new(|| {
-    let mut m = HashMap::new();
-    m.insert("gemma3-1b", HuggingFace { repo: "libretranslate/gemma3", model: "gemma-3-1b-it-q4_0.gguf" });
-    m.insert("gemma3-4b", HuggingFace { repo: "libretranslate/gemma3", model: "gemma-3-4b-it-q4_0.gguf" });
-    m.insert("gemma3-12b", HuggingFace { repo: "libretranslate/gemma3", model: "gemma-3-12b-it-q4_0.gguf" });
-    m.insert("gemma3-27b", HuggingFace { repo: "libretranslate/gemma3", model: "gemma-3-27b-it-q4_0.gguf" });
-    m
-});
-
-
 impl Model {
-    fn load(&self) -> Result<PathBuf> {
+    fn load(&self, opts: &DownloadOptions) -> Result<PathBuf> {
         match self {
             Model::Local { path } => {
                 if path.exists() && path.extension().and_then(|ext| ext.to_str()) == Some("gguf") {
@@ -39,30 +149,211 @@ impl Model {
                     Err(anyhow!(format!("Invalid path or not a .gguf file: {}", path.display())))
                 }
             },
-            Model::Remote { hf } => ApiBuilder::new()
-                .with_progress(true)
-                .build()
-                .with_context(|| "Unable to create HF API")?
-                .model(hf.repo.to_string())
-                .get(&hf.model)
-                .with_context(|| "Unable to download model"),
+            Model::Remote { hf, sha256 } => {
+                let repo = Repo::with_revision(hf.repo.clone(), RepoType::Model, hf.revision.clone());
+                let cache = match &opts.cache_dir {
+                    Some(dir) => Cache::new(dir.clone()),
+                    None => Cache::from_env()
+                };
+                let path = if opts.offline {
+                    cache.repo(repo).get(&hf.model).ok_or_else(|| anyhow!(
+                        "Model {}/{} is not cached in {} (offline mode)",
+                        hf.repo, hf.model, cache.path().display()
+                    ))?
+                } else {
+                    let mut builder = ApiBuilder::from_env().with_progress(true);
+                    if let Some(dir) = &opts.cache_dir {
+                        builder = builder.with_cache_dir(dir.clone());
+                    }
+                    if let Some(endpoint) = &opts.endpoint {
+                        builder = builder.with_endpoint(endpoint.clone());
+                    }
+
+                    builder.build()
+                        .with_context(|| "Unable to create HF API")?
+                        .repo(repo)
+                        .get(&hf.model)
+                        .map_err(|err| match err {
+                            ApiError::RequestError(_) | ApiError::TooManyRetries(_) => anyhow!(
+                                "Network failure while downloading {}/{}: {} (use --offline to load cached models only)",
+                                hf.repo, hf.model, err
+                            ),
+                            _ => anyhow!("Unable to download model {}/{}: {}", hf.repo, hf.model, err)
+                        })?
+                };
+
+                if let Some(expected) = sha256.as_ref().filter(|_| opts.verify) {
+                    verify_sha256(&path, expected, &cache.path().join(CHECKSUMS_FILE))?;
+                }
+
+                Ok(path)
+            }
+        }
+    }
+}
+
+/// Checksums of the verified model files, kept in the Hugging Face cache directory
+const CHECKSUMS_FILE: &str = "ltengine-checksums.json";
+
+/// A model file as it was when its checksum was computed
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+struct VerifiedFile {
+    size: u64,
+    modified: Duration,
+    sha256: String,
+}
+
+type Checksums = BTreeMap<PathBuf, VerifiedFile>;
+
+/// Checks the SHA256 of `path`, which is only computed again when the size or
+/// modification time of the file differ from the ones in `checksums`
+fn verify_sha256(path: &Path, expected: &str, checksums: &Path) -> Result<()> {
+    // Hugging Face snapshots are links to the blobs
+    let path = std::fs::canonicalize(path)
+        .with_context(|| format!("Unable to open model file: {}", path.display()))?;
+    let metadata = std::fs::metadata(&path)
+        .with_context(|| format!("Unable to open model file: {}", path.display()))?;
+    let size = metadata.len();
+    let modified = metadata.modified().ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default();
+
+    // A missing or invalid file only means that the models are hashed again
+    let mut verified: Checksums = std::fs::read_to_string(checksums).ok()
+        .and_then(|data| serde_json::from_str(&data).ok())
+        .unwrap_or_default();
+
+    let actual = match verified.get(&path) {
+        Some(file) if file.size == size && file.modified == modified => file.sha256.clone(),
+        _ => {
+            let sha256 = compute_sha256(&path)?;
+            verified.insert(path.clone(), VerifiedFile { size, modified, sha256: sha256.clone() });
+            let saved = serde_json::to_string_pretty(&verified)
+                .map_err(anyhow::Error::from)
+                .and_then(|data| Ok(std::fs::write(checksums, data)?));
+            if let Err(err) = saved {
+                warn!("Unable to save the checksum of {} to {}: {}", path.display(), checksums.display(), err);
+            }
+            sha256
+        }
+    };
+
+    if !actual.eq_ignore_ascii_case(expected) {
+        return Err(anyhow!(
+            "Model file {} is corrupt (expected SHA256 {}, got {}). Delete it to download it again.",
+            path.display(), expected, actual
+        ));
+    }
+
+    Ok(())
+}
+
+fn compute_sha256(path: &Path) -> Result<String> {
+    info!("Verifying checksum of {}", path.display());
+
+    let mut file = std::fs::File::open(path)
+        .with_context(|| format!("Unable to open model file: {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 1 << 20];
+    loop {
+        let n = file.read(&mut buf)
+            .with_context(|| format!("Unable to read model file: {}", path.display()))?;
+        if n == 0 {
+            break;
         }
+        hasher.update(&buf[..n]);
     }
+
+    Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
 }
 
-pub fn load_model(model_id: &String, model_file: &String) -> Result<PathBuf> {
+pub fn load_model(model: &ModelInfo, model_file: &String, opts: &DownloadOptions) -> Result<PathBuf> {
     let model = if !model_file.is_empty() {
         Model::Local {
             path: PathBuf::from(model_file),
         }
     } else {
         Model::Remote {
-            hf: MODELS[model_id.as_str()].clone(),
+            hf: model.hf(),
+            sha256: model.sha256.clone(),
         }
     };
 
-    match model.load() {
+    match model.load(opts) {
         Ok(path) => Ok(path),
         Err(e) => Err(e),
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Not the checksum of the test model
+    const OTHER_SHA256: &str = "5a2bd8d1d9c8b7e4d4f2c4e1b7c8f54a6e3a3bb8b0a8e4a9f5c0b6d3e7f1a2c4";
+
+    struct TempFiles {
+        model: PathBuf,
+        checksums: PathBuf,
+    }
+
+    impl TempFiles {
+        fn new() -> TempFiles {
+            let id = uuid::Uuid::new_v4();
+            let dir = std::env::temp_dir();
+            let files = TempFiles {
+                model: dir.join(format!("ltengine-model-{}.gguf", id)),
+                checksums: dir.join(format!("ltengine-checksums-{}.json", id)),
+            };
+            std::fs::write(&files.model, "model").unwrap();
+            files
+        }
+
+        fn checksums(&self) -> Checksums {
+            serde_json::from_str(&std::fs::read_to_string(&self.checksums).unwrap()).unwrap()
+        }
+    }
+
+    impl Drop for TempFiles {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.model);
+            let _ = std::fs::remove_file(&self.checksums);
+        }
+    }
+
+    #[test]
+    fn accepts_matching_checksum() {
+        let files = TempFiles::new();
+        let sha256 = compute_sha256(&files.model).unwrap();
+        assert!(verify_sha256(&files.model, &sha256.to_uppercase(), &files.checksums).is_ok());
+    }
+
+    #[test]
+    fn rejects_mismatching_checksum() {
+        let files = TempFiles::new();
+        let sha256 = compute_sha256(&files.model).unwrap();
+        let err = verify_sha256(&files.model, &"0".repeat(64), &files.checksums).unwrap_err().to_string();
+        assert!(err.contains("is corrupt"), "{}", err);
+        assert!(err.contains(&sha256), "{}", err);
+    }
+
+    #[test]
+    fn reuses_checksum_of_unchanged_file() {
+        let files = TempFiles::new();
+        verify_sha256(&files.model, &compute_sha256(&files.model).unwrap(), &files.checksums).unwrap();
+
+        // The file is not hashed again, so the recorded checksum is trusted
+        let mut checksums = files.checksums();
+        for file in checksums.values_mut() {
+            file.sha256 = OTHER_SHA256.to_string();
+        }
+        std::fs::write(&files.checksums, serde_json::to_string(&checksums).unwrap()).unwrap();
+        assert!(verify_sha256(&files.model, OTHER_SHA256, &files.checksums).is_ok());
+
+        // Unless its size changed
+        std::fs::write(&files.model, "modified model").unwrap();
+        assert!(verify_sha256(&files.model, OTHER_SHA256, &files.checksums).is_err());
+        let sha256 = compute_sha256(&files.model).unwrap();
+        assert!(files.checksums().values().all(|file| file.sha256 == sha256));
+    }
+}
@@ -0,0 +1,36 @@
+use utoipa::OpenApi;
+
+/// OpenAPI document of the public API, served at `/spec` and browsable at `/docs`
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "LTEngine",
+        description = "Local machine translation with LLMs, compatible with the LibreTranslate API"
+    ),
+    paths(
+        crate::translate,
+        crate::detect,
+        crate::get_languages,
+        crate::suggest,
+        crate::get_frontend_settings,
+        crate::translate_file,
+        crate::get_health,
+        crate::get_ready,
+        crate::get_metrics,
+    ),
+    components(schemas(
+        // Responses of the admin API, which has no documented paths
+        crate::AdminModelsResponse,
+        crate::AdminModel,
+        crate::AdminLoadResponse,
+        crate::AdminUnloadResponse,
+        crate::AdminRevokeResponse,
+    )),
+    tags(
+        (name = "translate", description = "Translation and language detection"),
+        (name = "feedback", description = "Suggestions for better translations"),
+        (name = "frontend", description = "Web frontend"),
+        (name = "monitoring", description = "Health checks and metrics"),
+    )
+)]
+pub struct ApiDoc;
@@ -0,0 +1,182 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use llama_cpp_2::llama_backend::LlamaBackend;
+use anyhow::{anyhow, Context, Result};
+use tracing::info;
+use crate::languages::{get_language_from_code, LanguageSet, LANGUAGES};
+use crate::llm::{LLM, LLMOptions};
+use crate::metrics::METRICS;
+use crate::models::{DownloadOptions, ModelInfo, ModelRegistry, load_model};
+use crate::prompt::{PromptBuilder, PromptTemplate};
+use crate::queue::Priority;
+use crate::sampling::SamplingConfig;
+
+/// A model loaded in memory, ready to serve requests
+pub struct LoadedModel {
+    pub id: String,
+    pub info: ModelInfo,
+    pub llm: LLM,
+    pub languages: LanguageSet,
+    pub sampling: SamplingConfig,
+}
+
+impl LoadedModel {
+    /// Runs a short translation to check that inference works
+    pub fn warm_up(&self) -> Result<()> {
+        let target = get_language_from_code(&"es".to_string())
+            .filter(|l| self.languages.supports(l))
+            .or_else(|| LANGUAGES.iter().find(|l| self.languages.supports(l)))
+            .ok_or_else(|| anyhow!("Model {} does not support any language", self.id))?;
+
+        let mut pb = PromptBuilder::new(self.llm.prompt_template());
+        pb.set_target_language(target);
+        let prompt = pb.build(&"Hello!".to_string());
+
+        let mut sampling = self.sampling.clone();
+        sampling.max_tokens = Some(8);
+        let ticket = self.llm.enqueue("warm-up", Priority::Batch)?;
+        self.llm.run_prompt(ticket, prompt, &sampling)?;
+        Ok(())
+    }
+}
+
+/// Set of models served by this process. Models can be loaded and unloaded
+/// at runtime; requests in flight keep using the model they started with.
+pub struct ModelPool {
+    backend: Arc<LlamaBackend>,
+    registry: ModelRegistry,
+    download: DownloadOptions,
+    sampling: SamplingConfig,
+    explicit_sampling: HashSet<String>,
+    llm_options: LLMOptions,
+    default_model: String,
+    models: RwLock<HashMap<String, Arc<LoadedModel>>>,
+}
+
+impl ModelPool {
+    /// `explicit_sampling` lists the sampling options set by the user,
+    /// which take precedence over the recommendations of each model.
+    pub fn new(
+        backend: Arc<LlamaBackend>,
+        registry: ModelRegistry,
+        download: DownloadOptions,
+        sampling: SamplingConfig,
+        explicit_sampling: HashSet<String>,
+        llm_options: LLMOptions,
+        default_model: String,
+    ) -> ModelPool {
+        ModelPool {
+            backend,
+            registry,
+            download,
+            sampling,
+            explicit_sampling,
+            llm_options,
+            default_model,
+            models: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Loads a model from the registry (or from `model_file` if set) and makes it
+    /// available to requests, replacing any model previously loaded with the same id.
+    pub fn load(&self, id: &str, model_file: Option<&str>, prompt_template: Option<&Path>) -> Result<Arc<LoadedModel>> {
+        let info = self.registry.get(id)
+            .cloned()
+            .ok_or_else(|| anyhow!("Unknown model: {}", id))?;
+
+        let mut sampling = self.sampling.clone();
+        sampling.apply_recommended(&info.sampling, |opt| !self.explicit_sampling.contains(opt));
+        if let Some(context_length) = info.context_length {
+            sampling.max_tokens_limit = sampling.max_tokens_limit.min(context_length);
+        }
+        sampling.validate()
+            .map_err(|err| anyhow!("Invalid sampling configuration for {}: {}", id, err))?;
+
+        let template = match prompt_template {
+            Some(path) => PromptTemplate::load(path),
+            None => info.prompt_template()
+        }.with_context(|| "Failed to load prompt template")?;
+
+        let model_path = load_model(&info, &model_file.unwrap_or("").to_string(), &self.download)
+            .with_context(|| "Failed to load model")?;
+
+        info!("Loading model: {}", model_path.display());
+
+        // Contexts never grow beyond what the model supports, unless a size is set explicitly
+        let mut llm_options = self.llm_options.clone();
+        llm_options.max_ctx_size = llm_options.max_ctx_size.or(info.context_length);
+
+        let start = Instant::now();
+        let llm = LLM::new(self.backend.clone(), model_path, template, llm_options)
+            .with_context(|| "Failed to initialize LLM")?;
+        METRICS.record_model_load(id, start.elapsed());
+        let languages = LanguageSet::new(info.languages.as_deref());
+
+        let model = Arc::new(LoadedModel { id: id.to_string(), info, llm, languages, sampling });
+        self.models.write().unwrap().insert(id.to_string(), model.clone());
+        Ok(model)
+    }
+
+    pub fn unload(&self, id: &str) -> Result<()> {
+        if id == self.default_model {
+            return Err(anyhow!("The default model cannot be unloaded"));
+        }
+
+        match self.models.write().unwrap().remove(id) {
+            Some(_) => Ok(()),
+            None => Err(anyhow!("Model {} is not loaded", id))
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<Arc<LoadedModel>> {
+        self.models.read().unwrap().get(id).cloned()
+    }
+
+    pub fn default_model(&self) -> Option<Arc<LoadedModel>> {
+        self.get(&self.default_model)
+    }
+
+    pub fn default_model_id(&self) -> &str {
+        &self.default_model
+    }
+
+    pub fn loaded(&self) -> Vec<Arc<LoadedModel>> {
+        let mut models: Vec<Arc<LoadedModel>> = self.models.read().unwrap().values().cloned().collect();
+        models.sort_by(|a, b| a.id.cmp(&b.id));
+        models
+    }
+
+    /// Refuses new translations and waits up to `timeout` for the queued ones to complete
+    pub fn drain(&self, timeout: Duration) -> bool {
+        let models = self.loaded();
+        for model in &models {
+            model.llm.queue().stop_accepting();
+        }
+        let deadline = Instant::now() + timeout;
+        models.iter().all(|model| model.llm.queue().wait_idle(deadline.saturating_duration_since(Instant::now())))
+    }
+
+    /// Cuts the remaining translations short and waits up to `timeout` for the running ones to stop
+    pub fn close(&self, timeout: Duration) {
+        let models = self.loaded();
+        for model in &models {
+            model.llm.queue().close();
+        }
+        let deadline = Instant::now() + timeout;
+        for model in &models {
+            model.llm.queue().wait_idle(deadline.saturating_duration_since(Instant::now()));
+        }
+    }
+
+    /// Descriptions of the translations that did not complete because of `close`
+    pub fn unfinished(&self) -> Vec<serde_json::Value> {
+        self.loaded().iter().flat_map(|model| model.llm.queue().unfinished()).collect()
+    }
+
+    /// Longest time a loaded model has been running its current job
+    pub fn longest_job(&self) -> Option<Duration> {
+        self.models.read().unwrap().values().filter_map(|model| model.llm.running_for()).max()
+    }
+}
@@ -1,60 +1,395 @@
-pub struct PromptBuilder{
-    source_language: &'static str,
-    target_language: &'static str,
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use anyhow::{anyhow, Context, Result};
+use crate::languages::Language;
+
+// Variables that can be referenced from a prompt template
+const TEMPLATE_VARIABLES: [&str; 9] = [
+    "source_language", "target_language", "source_code", "target_code",
+    "text", "format", "html", "glossary", "context"
+];
+
+// Chat template used when the model does not ship one
+const FALLBACK_CHAT_TEMPLATE: &str = "chatml";
+
+/// Prompt templates use a small mustache-like syntax:
+///
+///  * `{{name}}` is replaced by the value of the variable
+///  * `{{#name}}...{{/name}}` is rendered only if the variable is not empty
+///  * `{{^name}}...{{/name}}` is rendered only if the variable is empty
+///
+/// `source_language` and `source_code` are empty when the source language is auto-detected
+/// and `html` is set when the format is HTML.
+#[derive(Clone, Debug, Deserialize)]
+pub struct PromptTemplate {
+    #[serde(default)]
+    pub system: String,
+    pub user: String,
+
+    /// Name of the llama.cpp chat template to use if the model does not provide one
+    #[serde(default)]
+    pub chat_template: Option<String>,
+
+    /// Feed the rendered prompt to the model as-is instead of applying the chat template
+    #[serde(default)]
+    pub raw: bool,
+}
+
+/// Prompt formats of the models we know about
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PromptStyle {
+    #[default]
+    Gemma,
+    Tower,
+    Alma,
+    Aya,
+    Qwen,
+    SeedX,
+}
+
+impl PromptStyle {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PromptStyle::Gemma => "gemma",
+            PromptStyle::Tower => "tower",
+            PromptStyle::Alma => "alma",
+            PromptStyle::Aya => "aya",
+            PromptStyle::Qwen => "qwen",
+            PromptStyle::SeedX => "seed-x",
+        }
+    }
+}
+
+impl Default for PromptTemplate {
+    fn default() -> Self {
+        PromptTemplate {
+            system: "You are an expert linguist, specializing in translation. You are able to capture the nuances of the languages you translate. You pay attention to masculine/feminine/plural and proper use of articles and grammar. You always provide natural sounding translations that fully preserve the meaning of the original text. You never provide explanations for your work. {{#html}}You must preserve all HTML tags and elements in the translation. {{/html}}You always answer with the translated text and nothing else.".to_string(),
+            user: concat!(
+                "{{#context}}Context: {{context}}\n\n{{/context}}",
+                "{{#glossary}}Use the following glossary:\n{{glossary}}\n\n{{/glossary}}",
+                "{{#source_language}}Translate the text below from {{source_language}} to {{target_language}}.\n\n{{source_language}}: {{text}}\n\n{{target_language}}:\n{{/source_language}}",
+                "{{^source_language}}Translate the text below to {{target_language}}.\n\nText: {{text}}\n\n{{target_language}}:\n{{/source_language}}"
+            ).to_string(),
+            chat_template: None,
+            raw: false,
+        }
+    }
+}
+
+impl PromptTemplate {
+    pub fn builtin(style: PromptStyle) -> PromptTemplate {
+        let template = |system: &str, user: &str, chat_template: Option<&str>, raw: bool| PromptTemplate {
+            system: system.to_string(),
+            user: user.to_string(),
+            chat_template: chat_template.map(|t| t.to_string()),
+            raw,
+        };
+
+        match style {
+            PromptStyle::Gemma => PromptTemplate::default(),
+            PromptStyle::Tower => template(
+                "",
+                concat!(
+                    "{{#source_language}}Translate the following text from {{source_language}} into {{target_language}}.\n{{source_language}}: {{text}}\n{{target_language}}:{{/source_language}}",
+                    "{{^source_language}}Translate the following text into {{target_language}}.\nText: {{text}}\n{{target_language}}:{{/source_language}}"
+                ),
+                Some("chatml"),
+                false
+            ),
+            PromptStyle::Alma => template(
+                "",
+                concat!(
+                    "{{#source_language}}Translate this from {{source_language}} to {{target_language}}:\n{{source_language}}: {{text}}\n{{target_language}}:{{/source_language}}",
+                    "{{^source_language}}Translate this to {{target_language}}:\n{{text}}\n{{target_language}}:{{/source_language}}"
+                ),
+                None,
+                true
+            ),
+            PromptStyle::Aya => template(
+                "",
+                "Translate the following text{{#source_language}} from {{source_language}}{{/source_language}} to {{target_language}}. {{#html}}Preserve all HTML tags. {{/html}}Only output the translation.\n\n{{text}}",
+                Some("command-r"),
+                false
+            ),
+            PromptStyle::Qwen => template(
+                "You are a professional translator. {{#html}}You must preserve all HTML tags and elements in the translation. {{/html}}You always answer with the translated text and nothing else.",
+                "Translate the following text{{#source_language}} from {{source_language}}{{/source_language}} to {{target_language}}.\n\n{{text}}",
+                Some("chatml"),
+                false
+            ),
+            PromptStyle::SeedX => template(
+                "",
+                "Translate the following {{#source_language}}{{source_language}} {{/source_language}}sentence into {{target_language}}:\n{{text}} <{{target_code}}>",
+                None,
+                true
+            ),
+        }
+    }
+
+    /// Loads a prompt template from a JSON file with a `user` key
+    /// and optionally `system`, `chat_template` and `raw` keys.
+    pub fn load(path: &Path) -> Result<PromptTemplate> {
+        let data = std::fs::read_to_string(path)
+            .with_context(|| format!("Unable to read prompt template: {}", path.display()))?;
+        let template: PromptTemplate = serde_json::from_str(&data)
+            .with_context(|| format!("Invalid prompt template: {}", path.display()))?;
+        template.validate()
+            .with_context(|| format!("Invalid prompt template: {}", path.display()))?;
+        Ok(template)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        validate_template(&self.system)?;
+        validate_template(&self.user)?;
+        Ok(())
+    }
+
+    pub fn chat_template_name(&self) -> &str {
+        self.chat_template.as_deref().unwrap_or(FALLBACK_CHAT_TEMPLATE)
+    }
+}
+
+fn validate_template(tpl: &str) -> Result<()> {
+    let mut rest = tpl;
+    let mut open_sections: Vec<&str> = Vec::new();
+
+    while let Some(start) = rest.find("{{") {
+        let end = rest[start..].find("}}").ok_or_else(|| anyhow!("Unclosed tag in template"))? + start;
+        let tag = rest[start + 2..end].trim();
+        let name = tag.trim_start_matches(['#', '^', '/']);
+
+        if !TEMPLATE_VARIABLES.contains(&name) {
+            return Err(anyhow!("Unknown template variable: {}", name));
+        }
+        if tag.starts_with('#') || tag.starts_with('^') {
+            open_sections.push(name);
+        } else if tag.starts_with('/') && open_sections.pop() != Some(name) {
+            return Err(anyhow!("Unexpected closing tag: {}", name));
+        }
+
+        rest = &rest[end + 2..];
+    }
+
+    match open_sections.pop() {
+        Some(name) => Err(anyhow!("Unclosed section: {}", name)),
+        None => Ok(())
+    }
+}
+
+fn render(tpl: &str, vars: &[(&str, &str)]) -> String {
+    let lookup = |name: &str| vars.iter().find(|(k, _)| *k == name).map(|(_, v)| *v).unwrap_or("");
+    let mut out = String::with_capacity(tpl.len());
+    let mut rest = tpl;
+
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find("}}").map(|e| e + start) else {
+            rest = &rest[start..];
+            break;
+        };
+        let tag = rest[start + 2..end].trim();
+        rest = &rest[end + 2..];
+
+        if let Some(name) = tag.strip_prefix('#').or_else(|| tag.strip_prefix('^')) {
+            let closing = format!("{{{{/{}}}}}", name);
+            let (inner, after) = match rest.find(&closing) {
+                Some(pos) => (&rest[..pos], &rest[pos + closing.len()..]),
+                None => (rest, "")
+            };
+            let inverted = tag.starts_with('^');
+            if lookup(name).is_empty() == inverted {
+                out.push_str(&render(inner, vars));
+            }
+            rest = after;
+        } else {
+            out.push_str(lookup(tag));
+        }
+    }
+
+    out.push_str(rest);
+    out
+}
+
+pub struct PromptBuilder<'a>{
+    template: &'a PromptTemplate,
+    source_language: Option<&'static Language>,
+    target_language: Option<&'static Language>,
     format: String,
+    glossary: String,
+    context: String,
 }
 
 pub struct Prompt{
     pub system: String,
-    pub user: String
+    pub user: String,
+    /// Whether the system prompt only depends on the template, the languages and the format,
+    /// so that its evaluation can be reused by other requests
+    pub cacheable: bool
 }
 
-impl PromptBuilder {
-    pub fn new() -> PromptBuilder {
+impl<'a> PromptBuilder<'a> {
+    pub fn new(template: &'a PromptTemplate) -> PromptBuilder<'a> {
         PromptBuilder {
-            source_language: "auto",
-            target_language: "English",
+            template,
+            source_language: None,
+            target_language: None,
             format: "text".to_string(),
+            glossary: String::new(),
+            context: String::new(),
         }
     }
 
-    pub fn set_format(&mut self, format: &str) -> &mut PromptBuilder {
+    pub fn set_format(&mut self, format: &str) -> &mut PromptBuilder<'a> {
         self.format = format.to_string();
         self
     }
 
 
-    pub fn set_source_language(&mut self, s: &'static str) -> &mut PromptBuilder {
+    /// Sets the source language, `None` if it should be auto-detected
+    pub fn set_source_language(&mut self, s: Option<&'static Language>) -> &mut PromptBuilder<'a> {
         self.source_language = s;
         self
     }
 
-    pub fn set_target_language(&mut self, t: &'static str) -> &mut PromptBuilder {
-        self.target_language = t;
+    pub fn set_target_language(&mut self, t: &'static Language) -> &mut PromptBuilder<'a> {
+        self.target_language = Some(t);
+        self
+    }
+
+    pub fn set_glossary(&mut self, glossary: &str) -> &mut PromptBuilder<'a> {
+        self.glossary = glossary.trim().to_string();
+        self
+    }
+
+    pub fn set_context(&mut self, context: &str) -> &mut PromptBuilder<'a> {
+        self.context = context.trim().to_string();
         self
     }
 
     pub fn build(&self, q: &String) -> Prompt {
-        let system = if self.format == "html" {
-            "You are an expert linguist, specializing in translation. You are able to capture the nuances of the languages you translate. You pay attention to masculine/feminine/plural and proper use of articles and grammar. You always provide natural sounding translations that fully preserve the meaning of the original text. You never provide explanations for your work. You must preserve all HTML tags and elements in the translation. You always answer with the translated text and nothing else."
-        } else {
-            "You are an expert linguist, specializing in translation. You are able to capture the nuances of the languages you translate. You pay attention to masculine/feminine/plural and proper use of articles and grammar. You always provide natural sounding translations that fully preserve the meaning of the original text. You never provide explanations for your work. You always answer with the translated text and nothing else."
-        }.to_string();
-
-
-        let user = (if self.source_language == "auto"{
-            format!(
-                "Translate the text below to {}.\n\nText: {}\n\n{}:\n",
-                self.target_language, q, self.target_language
-            )
-        }else{
-            format!(
-                "Translate the text below from {} to {}.\n\n{}: {}\n\n{}:\n",
-                self.source_language, self.target_language,
-                self.source_language, q, self.target_language
-            )
-        }).to_string();
-
-        Prompt { system, user }
-    }
-}
\ No newline at end of file
+        let html = if self.format == "html" { "true" } else { "" };
+        let vars = [
+            ("source_language", self.source_language.map_or("", |l| l.name)),
+            ("target_language", self.target_language.map_or("English", |l| l.name)),
+            ("source_code", self.source_language.map_or("", |l| l.internal_code)),
+            ("target_code", self.target_language.map_or("en", |l| l.internal_code)),
+            ("text", q.as_str()),
+            ("format", self.format.as_str()),
+            ("html", html),
+            ("glossary", self.glossary.as_str()),
+            ("context", self.context.as_str()),
+        ];
+
+        let system = render(&self.template.system, &vars);
+        // The text, glossary and context differ between requests
+        let shared_vars: Vec<(&str, &str)> = vars.iter()
+            .map(|&(k, v)| if matches!(k, "text" | "glossary" | "context") { (k, "") } else { (k, v) })
+            .collect();
+        let cacheable = render(&self.template.system, &shared_vars) == system;
+
+        Prompt {
+            system,
+            user: render(&self.template.user, &vars),
+            cacheable
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::languages::get_language_from_code;
+
+    const STYLES: [PromptStyle; 6] = [
+        PromptStyle::Gemma, PromptStyle::Tower, PromptStyle::Alma,
+        PromptStyle::Aya, PromptStyle::Qwen, PromptStyle::SeedX,
+    ];
+
+    fn language(code: &str) -> &'static Language {
+        get_language_from_code(&code.to_string()).unwrap()
+    }
+
+    fn error(tpl: &str) -> String {
+        validate_template(tpl).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn validates_templates() {
+        assert!(validate_template("{{#source_language}}{{source_language}}: {{/source_language}}{{text}}").is_ok());
+        assert!(validate_template("{{ text }} {{^html}}plain{{/html}}").is_ok());
+        assert!(validate_template("No variables").is_ok());
+    }
+
+    #[test]
+    fn rejects_unknown_tags() {
+        assert_eq!(error("{{txt}}"), "Unknown template variable: txt");
+        assert_eq!(error("{{#language}}{{/language}}"), "Unknown template variable: language");
+    }
+
+    #[test]
+    fn rejects_unbalanced_sections() {
+        assert_eq!(error("{{#glossary}}{{glossary}}"), "Unclosed section: glossary");
+        assert_eq!(error("{{text}}{{/context}}"), "Unexpected closing tag: context");
+        assert_eq!(error("{{#glossary}}{{#context}}{{/glossary}}{{/context}}"), "Unexpected closing tag: glossary");
+        assert_eq!(error("{{text"), "Unclosed tag in template");
+    }
+
+    #[test]
+    fn renders_variables_and_sections() {
+        let vars = [("text", "Hello"), ("html", "")];
+        assert_eq!(render("{{text}}{{#html}} <b>{{/html}}{{^html}} plain{{/html}}", &vars), "Hello plain");
+        assert_eq!(render("{{ text }}, {{glossary}}!", &vars), "Hello, !");
+    }
+
+    #[test]
+    fn renders_nested_sections() {
+        let tpl = "{{#glossary}}Glossary: {{glossary}}{{#context}}, context: {{context}}{{/context}}.{{/glossary}}{{text}}";
+        let vars = |glossary, context| [("glossary", glossary), ("context", context), ("text", "Hi")];
+        assert_eq!(render(tpl, &vars("a=b", "c")), "Glossary: a=b, context: c.Hi");
+        assert_eq!(render(tpl, &vars("a=b", "")), "Glossary: a=b.Hi");
+        assert_eq!(render(tpl, &vars("", "c")), "Hi");
+    }
+
+    #[test]
+    fn renders_builtin_styles() {
+        let text = "Good morning".to_string();
+        let source = Some(language("en"));
+        let target = language("it");
+
+        for style in STYLES {
+            let template = PromptTemplate::builtin(style);
+            assert!(template.validate().is_ok(), "{}", style.name());
+
+            for (source, format) in [(source, "text"), (None, "text"), (source, "html")] {
+                let mut pb = PromptBuilder::new(&template);
+                pb.set_format(format)
+                    .set_source_language(source)
+                    .set_target_language(target)
+                    .set_glossary("morning = mattina")
+                    .set_context("A greeting");
+                let prompt = pb.build(&text);
+
+                for rendered in [&prompt.system, &prompt.user] {
+                    assert!(!rendered.contains("{{") && !rendered.contains("}}"), "{}: {}", style.name(), rendered);
+                }
+                assert!(prompt.user.contains(&text), "{}: {}", style.name(), prompt.user);
+                assert!(prompt.user.contains(target.name), "{}: {}", style.name(), prompt.user);
+                assert_eq!(prompt.user.contains("English"), source.is_some(), "{}: {}", style.name(), prompt.user);
+            }
+        }
+    }
+
+    #[test]
+    fn renders_glossary_and_context_outside_the_system_prompt() {
+        let template = PromptTemplate::default();
+        let mut pb = PromptBuilder::new(&template);
+        pb.set_target_language(language("it"))
+            .set_glossary(" morning = mattina \n")
+            .set_context("A greeting");
+        let prompt = pb.build(&"Good morning".to_string());
+
+        assert!(prompt.cacheable);
+        assert!(prompt.user.starts_with("Context: A greeting\n\nUse the following glossary:\nmorning = mattina\n\n"), "{}", prompt.user);
+    }
+}
@@ -0,0 +1,511 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+use serde_json::Value;
+use crate::metrics::METRICS;
+
+/// Priority classes, served in this order
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Requests of users waiting for the result (e.g. `/translate`)
+    Interactive,
+    /// File translations, batch calls and background work
+    Batch,
+}
+
+const PRIORITIES: usize = 2;
+
+// Weight of the last job in the average job duration used for ETAs
+const DURATION_SMOOTHING: f64 = 0.2;
+
+/// Waiting jobs of one priority class. Clients are served round-robin
+/// and the jobs of each client in FIFO order.
+#[derive(Default)]
+struct Class {
+    clients: VecDeque<String>,
+    jobs: HashMap<String, VecDeque<u64>>,
+    len: usize,
+}
+
+impl Class {
+    fn push(&mut self, client: &str, id: u64) {
+        let jobs = self.jobs.entry(client.to_string()).or_default();
+        if jobs.is_empty() {
+            self.clients.push_back(client.to_string());
+        }
+        jobs.push_back(id);
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<u64> {
+        let client = self.clients.pop_front()?;
+        let jobs = self.jobs.get_mut(&client)?;
+        let id = jobs.pop_front();
+        if jobs.is_empty() {
+            self.jobs.remove(&client);
+        } else {
+            self.clients.push_back(client);
+        }
+        self.len -= 1;
+        id
+    }
+
+    /// Number of jobs served before a new job of `client`: it is the client's k-th job,
+    /// so it runs in the k-th round, after up to k jobs of the clients ahead in the rotation
+    /// and up to k - 1 jobs of those behind
+    fn position(&self, client: &str) -> usize {
+        let k = self.jobs.get(client).map_or(0, |jobs| jobs.len()) + 1;
+        let index = self.clients.iter().position(|c| c == client).unwrap_or(self.clients.len());
+        self.clients.iter().enumerate()
+            .map(|(i, c)| {
+                let len = self.jobs.get(c).map_or(0, |jobs| jobs.len());
+                if i < index { len.min(k) } else { len.min(k - 1) }
+            })
+            .sum()
+    }
+
+    fn remove(&mut self, client: &str, id: u64) {
+        let Some(jobs) = self.jobs.get_mut(client) else {
+            return;
+        };
+        let before = jobs.len();
+        jobs.retain(|job| *job != id);
+        self.len -= before - jobs.len();
+        if jobs.is_empty() {
+            self.jobs.remove(client);
+            self.clients.retain(|c| c != client);
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    classes: [Class; PRIORITIES],
+    /// Job allowed to run
+    current: Option<u64>,
+    /// When the running job started using the LLM
+    started: Option<Instant>,
+    next_id: u64,
+    /// Average time a job holds the LLM, in seconds
+    avg_duration: f64,
+    /// New jobs are refused, those already queued still run
+    draining: bool,
+    /// No more jobs are scheduled
+    closed: bool,
+    /// Descriptions of the jobs in the queue or running
+    jobs: HashMap<u64, Value>,
+    /// Descriptions of the jobs whose result was not delivered because of `close`
+    unfinished: Vec<Value>,
+}
+
+impl State {
+    fn waiting(&self) -> usize {
+        self.classes.iter().map(|c| c.len).sum()
+    }
+
+    /// Lets the next job run if the LLM is free
+    fn schedule(&mut self) {
+        if self.current.is_none() && !self.closed {
+            self.current = self.classes.iter_mut().find_map(|c| c.pop());
+        }
+    }
+}
+
+struct Inner {
+    state: Mutex<State>,
+    cond: Condvar,
+    max_len: usize,
+}
+
+/// Queue in front of an LLM, which can only run one job at a time
+#[derive(Clone)]
+pub struct InferenceQueue {
+    inner: Arc<Inner>,
+}
+
+/// The queue is full, the caller can retry after `eta`
+#[derive(Debug)]
+pub struct QueueFull {
+    pub eta: Duration,
+}
+
+impl Display for QueueFull {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "The queue is full")
+    }
+}
+
+impl std::error::Error for QueueFull {}
+
+/// The queue was shut down before the job could run
+#[derive(Debug)]
+pub struct QueueClosed;
+
+impl Display for QueueClosed {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "The server is shutting down")
+    }
+}
+
+impl std::error::Error for QueueClosed {}
+
+/// A place in the queue. Dropping it leaves the queue.
+pub struct Ticket {
+    inner: Arc<Inner>,
+    id: u64,
+    client: String,
+    priority: Priority,
+    enqueued: Instant,
+    active: bool,
+    /// Enqueued after the queue stopped accepting jobs
+    refused: bool,
+    /// Jobs that will run before this one (at the time it was queued)
+    pub position: usize,
+    /// Estimated time before this job runs
+    pub eta: Duration,
+}
+
+/// Description of a job, saved by `unfinished` if the queue is closed before
+/// its result is delivered
+pub struct Job {
+    inner: Arc<Inner>,
+    id: u64,
+    delivered: bool,
+}
+
+/// Permission to use the LLM, until dropped
+pub struct Permit {
+    inner: Arc<Inner>,
+    started: Instant,
+    /// Time spent in the queue
+    pub waited: Duration,
+}
+
+impl InferenceQueue {
+    /// `max_len` is the maximum number of waiting jobs (0 = unlimited)
+    pub fn new(max_len: usize) -> InferenceQueue {
+        InferenceQueue {
+            inner: Arc::new(Inner {
+                state: Mutex::new(State::default()),
+                cond: Condvar::new(),
+                max_len,
+            }),
+        }
+    }
+
+    pub fn enqueue(&self, client: &str, priority: Priority) -> Result<Ticket, QueueFull> {
+        let mut state = self.inner.state.lock().unwrap();
+
+        let running = state.current.is_some() as usize;
+        let position = running
+            + state.classes[..priority as usize].iter().map(|c| c.len).sum::<usize>()
+            + state.classes[priority as usize].position(client);
+        let eta = Duration::from_secs_f64(position as f64 * state.avg_duration);
+
+        if self.inner.max_len > 0 && state.waiting() >= self.inner.max_len {
+            return Err(QueueFull { eta });
+        }
+
+        let id = state.next_id;
+        state.next_id += 1;
+        // Refused tickets fail with `QueueClosed` when they wait
+        let refused = state.draining || state.closed;
+        if !refused {
+            state.classes[priority as usize].push(client, id);
+            state.schedule();
+            METRICS.enter_queue();
+        }
+
+        Ok(Ticket {
+            inner: self.inner.clone(),
+            id,
+            client: client.to_string(),
+            priority,
+            enqueued: Instant::now(),
+            active: !refused,
+            refused,
+            position,
+            eta,
+        })
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.inner.state.lock().unwrap().closed
+    }
+
+    /// How long the running job has been using the LLM
+    pub fn running_for(&self) -> Option<Duration> {
+        self.inner.state.lock().unwrap().started.map(|started| started.elapsed())
+    }
+
+    /// Refuses new jobs, which fail with `QueueClosed`. The queued ones still run.
+    pub fn stop_accepting(&self) {
+        self.inner.state.lock().unwrap().draining = true;
+    }
+
+    /// Waits up to `timeout` for the queued jobs to complete.
+    /// Returns whether the queue is empty and the LLM free.
+    pub fn wait_idle(&self, timeout: Duration) -> bool {
+        let mut state = self.inner.state.lock().unwrap();
+        let deadline = Instant::now() + timeout;
+        while state.current.is_some() || state.waiting() > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            state = self.inner.cond.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        true
+    }
+
+    /// Stops scheduling jobs: waiting and new jobs fail with `QueueClosed` and the running one
+    /// is expected to stop (see `wait_idle`)
+    pub fn close(&self) {
+        self.inner.state.lock().unwrap().closed = true;
+        self.inner.cond.notify_all();
+    }
+
+    /// Takes the descriptions of the jobs that did not complete because of `close`
+    pub fn unfinished(&self) -> Vec<Value> {
+        let mut state = self.inner.state.lock().unwrap();
+        let mut jobs = std::mem::take(&mut state.unfinished);
+        jobs.extend(state.jobs.drain().map(|(_, job)| job));
+        jobs
+    }
+}
+
+impl Ticket {
+    /// Describes the job, so that it can be saved if the server shuts down before its result
+    /// is delivered. The description is kept until the returned `Job` is dropped.
+    pub fn describe(&self, job: Value) -> Job {
+        if !self.refused {
+            self.inner.state.lock().unwrap().jobs.insert(self.id, job);
+        }
+        Job { inner: self.inner.clone(), id: self.id, delivered: false }
+    }
+
+    /// Blocks until it is this job's turn to use the LLM
+    pub fn wait(mut self) -> Result<Permit, QueueClosed> {
+        if self.refused {
+            return Err(QueueClosed);
+        }
+        let mut state = self.inner.state.lock().unwrap();
+        while state.current != Some(self.id) && !state.closed {
+            state = self.inner.cond.wait(state).unwrap();
+        }
+        if state.current != Some(self.id) {
+            // Dropping the ticket leaves the queue
+            drop(state);
+            return Err(QueueClosed);
+        }
+        let started = Instant::now();
+        state.started = Some(started);
+        drop(state);
+
+        self.active = false;
+        let waited = self.enqueued.elapsed();
+        METRICS.leave_queue(waited);
+        Ok(Permit { inner: self.inner.clone(), started, waited })
+    }
+}
+
+impl Job {
+    /// The result was sent to the client, the job does not need to be saved
+    pub fn delivered(mut self) {
+        self.delivered = true;
+    }
+}
+
+impl Drop for Job {
+    fn drop(&mut self) {
+        let mut state = self.inner.state.lock().unwrap();
+        if let Some(job) = state.jobs.remove(&self.id).filter(|_| !self.delivered && state.closed) {
+            state.unfinished.push(job);
+        }
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        if !self.active {
+            return;
+        }
+
+        let mut state = self.inner.state.lock().unwrap();
+        if state.current == Some(self.id) {
+            state.current = None;
+        } else {
+            state.classes[self.priority as usize].remove(&self.client, self.id);
+        }
+        state.schedule();
+        drop(state);
+
+        METRICS.leave_queue(self.enqueued.elapsed());
+        self.inner.cond.notify_all();
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut state = self.inner.state.lock().unwrap();
+        let duration = self.started.elapsed().as_secs_f64();
+        state.avg_duration = if state.avg_duration == 0.0 {
+            duration
+        } else {
+            state.avg_duration * (1.0 - DURATION_SMOOTHING) + duration * DURATION_SMOOTHING
+        };
+        state.current = None;
+        state.started = None;
+        state.schedule();
+        drop(state);
+
+        self.inner.cond.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn closed_queue_fails_tickets() {
+        let queue = InferenceQueue::new(0);
+        let running = queue.enqueue("a", Priority::Interactive).unwrap().wait().unwrap();
+        let waiting = queue.enqueue("b", Priority::Interactive).unwrap();
+
+        queue.close();
+        assert!(waiting.wait().is_err());
+        assert!(queue.enqueue("c", Priority::Interactive).unwrap().wait().is_err());
+        drop(running);
+        assert!(queue.wait_idle(Duration::ZERO));
+    }
+
+    #[test]
+    fn draining_queue_runs_queued_jobs_only() {
+        let queue = InferenceQueue::new(0);
+        let running = queue.enqueue("a", Priority::Interactive).unwrap().wait().unwrap();
+        let waiting = queue.enqueue("b", Priority::Interactive).unwrap();
+
+        queue.stop_accepting();
+        let refused = queue.enqueue("c", Priority::Interactive).unwrap();
+        assert!(!queue.wait_idle(Duration::from_millis(10)));
+
+        drop(running);
+        drop(waiting.wait().unwrap());
+        assert!(refused.wait().is_err());
+        assert!(queue.wait_idle(Duration::ZERO));
+    }
+
+    #[test]
+    fn saves_jobs_not_delivered_before_close() {
+        let queue = InferenceQueue::new(0);
+        let ticket = queue.enqueue("a", Priority::Interactive).unwrap();
+        let running = ticket.describe(serde_json::json!("running"));
+        let permit = ticket.wait().unwrap();
+        let ticket = queue.enqueue("b", Priority::Interactive).unwrap();
+        let waiting = ticket.describe(serde_json::json!("waiting"));
+        let ticket = queue.enqueue("c", Priority::Interactive).unwrap();
+        let delivered = ticket.describe(serde_json::json!("delivered"));
+
+        queue.close();
+        drop(permit);
+        // The result of a job can be lost after it completes, e.g. if its client is gone
+        drop(running);
+        drop(waiting);
+        delivered.delivered();
+
+        let mut unfinished = queue.unfinished();
+        unfinished.sort_by_key(|job| job.to_string());
+        assert_eq!(unfinished, [serde_json::json!("running"), serde_json::json!("waiting")]);
+        assert!(queue.unfinished().is_empty());
+    }
+
+    #[test]
+    fn forgets_jobs_completed_before_close() {
+        let queue = InferenceQueue::new(0);
+        let ticket = queue.enqueue("a", Priority::Interactive).unwrap();
+        let job = ticket.describe(serde_json::json!("done"));
+        drop(ticket.wait().unwrap());
+        drop(job);
+
+        queue.close();
+        assert!(queue.unfinished().is_empty());
+    }
+
+    /// Order in which the waiting tickets get the LLM, running each job immediately
+    fn run_order(queue: &InferenceQueue, mut tickets: Vec<(&'static str, Ticket)>) -> Vec<&'static str> {
+        let mut order = Vec::new();
+        while !tickets.is_empty() {
+            let current = queue.inner.state.lock().unwrap().current;
+            let index = tickets.iter().position(|(_, t)| Some(t.id) == current).unwrap();
+            let (name, ticket) = tickets.remove(index);
+            drop(ticket.wait().unwrap());
+            order.push(name);
+        }
+        order
+    }
+
+    #[test]
+    fn serves_clients_round_robin() {
+        let queue = InferenceQueue::new(0);
+        let running = queue.enqueue("x", Priority::Interactive).unwrap().wait().unwrap();
+        let mut tickets = Vec::new();
+        for name in ["a1", "a2", "a3", "b1", "c1", "b2"] {
+            tickets.push((name, queue.enqueue(&name[..1], Priority::Interactive).unwrap()));
+        }
+        drop(running);
+        assert_eq!(run_order(&queue, tickets), ["a1", "b1", "c1", "a2", "b2", "a3"]);
+    }
+
+    #[test]
+    fn serves_interactive_before_batch() {
+        let queue = InferenceQueue::new(0);
+        let running = queue.enqueue("x", Priority::Batch).unwrap().wait().unwrap();
+        let tickets = vec![
+            ("batch", queue.enqueue("a", Priority::Batch).unwrap()),
+            ("interactive", queue.enqueue("b", Priority::Interactive).unwrap()),
+        ];
+        drop(running);
+        assert_eq!(run_order(&queue, tickets), ["interactive", "batch"]);
+    }
+
+    #[test]
+    fn estimates_position_in_rotation() {
+        let queue = InferenceQueue::new(0);
+        let _running = queue.enqueue("x", Priority::Interactive).unwrap().wait().unwrap();
+        let mut tickets = Vec::new();
+        for client in ["a", "a", "a", "b"] {
+            tickets.push(queue.enqueue(client, Priority::Interactive).unwrap());
+        }
+        let positions: Vec<usize> = tickets.iter().map(|t| t.position).collect();
+        assert_eq!(positions, [1, 2, 3, 2]);
+
+        // c only waits for one job of each client ahead of it
+        tickets.push(queue.enqueue("c", Priority::Interactive).unwrap());
+        assert_eq!(tickets[4].position, 3);
+        // Batch jobs wait for all the interactive ones
+        tickets.push(queue.enqueue("c", Priority::Batch).unwrap());
+        assert_eq!(tickets[5].position, 6);
+        // Interactive jobs ignore the batch ones
+        assert_eq!(queue.enqueue("d", Priority::Interactive).unwrap().position, 4);
+    }
+
+    #[test]
+    fn estimates_eta_from_job_durations() {
+        let queue = InferenceQueue::new(0);
+        queue.inner.state.lock().unwrap().avg_duration = 2.0;
+        let _running = queue.enqueue("a", Priority::Interactive).unwrap().wait().unwrap();
+        let ticket = queue.enqueue("b", Priority::Interactive).unwrap();
+        assert_eq!(ticket.eta, Duration::from_secs(2));
+        assert_eq!(queue.enqueue("c", Priority::Interactive).unwrap().eta, Duration::from_secs(4));
+    }
+
+    #[test]
+    fn refuses_jobs_when_full() {
+        let queue = InferenceQueue::new(1);
+        let _running = queue.enqueue("a", Priority::Interactive).unwrap().wait().unwrap();
+        let _waiting = queue.enqueue("b", Priority::Interactive).unwrap();
+        assert!(queue.enqueue("c", Priority::Interactive).is_err());
+    }
+}
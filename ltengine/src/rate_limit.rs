@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use actix_web::HttpRequest;
+
+// Forget clients that have not been seen for this long
+const IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+const MAX_CLIENTS: usize = 10_000;
+// Share of the clients evicted when all of them are active
+const EVICTED_CLIENTS: usize = MAX_CLIENTS / 10;
+
+/// Limits per minute (None = unlimited)
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Limits {
+    pub requests: Option<u32>,
+    pub chars: Option<u32>,
+}
+
+/// Token bucket holding up to one minute worth of tokens, refilled continuously
+struct Bucket {
+    tokens: f64,
+}
+
+impl Bucket {
+    fn refill(&mut self, per_minute: f64, elapsed: Duration) {
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * per_minute / 60.0).min(per_minute);
+    }
+
+    /// Seconds to wait until `amount` tokens are available
+    fn wait_time(&self, per_minute: f64, amount: f64) -> f64 {
+        // Requests larger than the bucket only need a full bucket
+        let amount = amount.min(per_minute);
+        if self.tokens >= amount { 0.0 } else { (amount - self.tokens) * 60.0 / per_minute }
+    }
+}
+
+struct ClientState {
+    requests: Bucket,
+    chars: Bucket,
+    updated: Instant,
+}
+
+pub struct RateLimiter {
+    limits: Limits,
+    clients: Mutex<HashMap<String, ClientState>>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: Limits) -> RateLimiter {
+        RateLimiter { limits, clients: Mutex::new(HashMap::new()) }
+    }
+
+    /// Default limits of each client
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    /// Takes one request and `chars` characters from the buckets of `client`.
+    /// Returns the number of seconds to wait before retrying if `client` is over its `limits`.
+    pub fn check(&self, client: &str, limits: Limits, chars: usize) -> Result<(), u64> {
+        if limits.requests.is_none() && limits.chars.is_none() {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        let requests_per_minute = limits.requests.map(|l| l as f64);
+        let chars_per_minute = limits.chars.map(|l| l as f64);
+
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() >= MAX_CLIENTS && !clients.contains_key(client) {
+            evict(&mut clients, now);
+        }
+
+        let state = clients.entry(client.to_string()).or_insert_with(|| ClientState {
+            requests: Bucket { tokens: requests_per_minute.unwrap_or(0.0) },
+            chars: Bucket { tokens: chars_per_minute.unwrap_or(0.0) },
+            updated: now,
+        });
+
+        let elapsed = now.duration_since(state.updated);
+        state.updated = now;
+
+        let mut wait: f64 = 0.0;
+        if let Some(per_minute) = requests_per_minute {
+            state.requests.refill(per_minute, elapsed);
+            wait = wait.max(state.requests.wait_time(per_minute, 1.0));
+        }
+        if let Some(per_minute) = chars_per_minute {
+            state.chars.refill(per_minute, elapsed);
+            wait = wait.max(state.chars.wait_time(per_minute, chars as f64));
+        }
+        if wait > 0.0 {
+            return Err(wait.ceil() as u64);
+        }
+
+        if let Some(per_minute) = requests_per_minute {
+            state.requests.tokens -= 1.0_f64.min(per_minute);
+        }
+        if let Some(per_minute) = chars_per_minute {
+            state.chars.tokens -= (chars as f64).min(per_minute);
+        }
+        Ok(())
+    }
+}
+
+/// Forgets idle clients, or the least recently seen ones if they are all active
+fn evict(clients: &mut HashMap<String, ClientState>, now: Instant) {
+    clients.retain(|_, state| now.duration_since(state.updated) < IDLE_TIMEOUT);
+    if clients.len() < MAX_CLIENTS {
+        return;
+    }
+
+    let mut updated: Vec<Instant> = clients.values().map(|state| state.updated).collect();
+    let (_, cutoff, _) = updated.select_nth_unstable(EVICTED_CLIENTS);
+    let cutoff = *cutoff;
+    clients.retain(|_, state| state.updated >= cutoff);
+}
+
+/// Address of the client that sent `req`. `X-Forwarded-For` is only used
+/// when the request comes from one of the `trusted_proxies` or from the Unix socket,
+/// which only local processes (e.g. a reverse proxy) can connect to.
+pub fn client_ip(req: &HttpRequest, trusted_proxies: &[IpAddr]) -> Option<IpAddr> {
+    // Unix socket peers have no address
+    let peer = req.peer_addr().map(|addr| addr.ip());
+    if let Some(peer) = peer.filter(|ip| !trusted_proxies.contains(ip)) {
+        return Some(peer);
+    }
+
+    let forwarded: Vec<IpAddr> = req.headers().get_all("x-forwarded-for")
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|ip| ip.trim().parse().ok())
+        .collect();
+
+    // The rightmost address that is not a trusted proxy is the client
+    forwarded.iter().rev()
+        .find(|ip| !trusted_proxies.contains(ip))
+        .or(forwarded.first())
+        .copied()
+        .or(peer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refills_up_to_one_minute() {
+        let mut bucket = Bucket { tokens: 0.0 };
+        bucket.refill(60.0, Duration::from_secs(10));
+        assert_eq!(bucket.tokens, 10.0);
+        bucket.refill(60.0, Duration::from_secs(600));
+        assert_eq!(bucket.tokens, 60.0);
+    }
+
+    #[test]
+    fn waits_for_missing_tokens() {
+        let bucket = Bucket { tokens: 2.0 };
+        assert_eq!(bucket.wait_time(60.0, 1.0), 0.0);
+        assert_eq!(bucket.wait_time(60.0, 2.0), 0.0);
+        assert_eq!(bucket.wait_time(60.0, 5.0), 3.0);
+        assert_eq!(bucket.wait_time(30.0, 5.0), 6.0);
+    }
+
+    #[test]
+    fn large_requests_wait_for_a_full_bucket() {
+        let bucket = Bucket { tokens: 0.0 };
+        assert_eq!(bucket.wait_time(60.0, 1000.0), 60.0);
+    }
+
+    #[test]
+    fn limits_each_client() {
+        let limiter = RateLimiter::new(Limits::default());
+        let limits = Limits { requests: Some(2), chars: Some(100) };
+        assert!(limiter.check("a", limits, 10).is_ok());
+        assert!(limiter.check("a", limits, 10).is_ok());
+        assert!(limiter.check("a", limits, 10).is_err());
+        assert!(limiter.check("b", limits, 10).is_ok());
+        assert!(limiter.check("c", limits, 90).is_ok());
+        assert!(limiter.check("c", limits, 20).is_err());
+    }
+
+    #[test]
+    fn evicts_least_recently_seen_clients() {
+        let limiter = RateLimiter::new(Limits::default());
+        let limits = Limits { requests: Some(1), chars: None };
+        for i in 0..MAX_CLIENTS {
+            limiter.check(&i.to_string(), limits, 0).unwrap();
+        }
+        assert!(limiter.check("new", limits, 0).is_ok());
+
+        let clients = limiter.clients.lock().unwrap();
+        assert!(clients.len() <= MAX_CLIENTS - EVICTED_CLIENTS + 1);
+        assert!(clients.contains_key("new"));
+        assert!(clients.contains_key(&(MAX_CLIENTS - 1).to_string()));
+        assert!(!clients.contains_key("0"));
+    }
+}
@@ -0,0 +1,205 @@
+use std::path::Path;
+use std::str::FromStr;
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use crate::languages::Language;
+
+#[derive(Clone, Debug)]
+pub enum RouteCondition {
+    /// Requests with the given format (text, html)
+    Format(String),
+    /// Requests with at least this many characters
+    MinChars(usize),
+    /// Requests for a language pair, `*` matches any language
+    Pair { source: String, target: String },
+}
+
+/// Sends requests matching `condition` to `model`
+#[derive(Clone, Debug)]
+pub struct RouteRule {
+    pub condition: RouteCondition,
+    pub model: String,
+}
+
+/// Properties of a request used for routing
+pub struct RouteRequest<'a> {
+    pub format: &'a str,
+    pub chars: usize,
+    /// Source language, as requested or auto-detected
+    pub source: &'a Language,
+    pub target: &'a Language,
+}
+
+impl FromStr for RouteRule {
+    type Err = String;
+
+    /// Parses rules such as `format:html=gemma3-27b`, `min-chars:2000=gemma3-27b` or `pair:en/ja=gemma3-27b`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (condition, model) = s.split_once('=')
+            .ok_or_else(|| format!("Invalid route {}, expected CONDITION=MODEL", s))?;
+        let (kind, value) = condition.split_once(':')
+            .ok_or_else(|| format!("Invalid route condition {}, expected KIND:VALUE", condition))?;
+
+        let condition = match kind.trim() {
+            "format" => RouteCondition::Format(value.trim().to_string()),
+            "min-chars" => RouteCondition::MinChars(value.trim().parse()
+                .map_err(|_| format!("Invalid number of characters: {}", value))?),
+            "pair" => {
+                let (source, target) = value.trim().split_once('/')
+                    .filter(|(source, target)| !source.trim().is_empty() && !target.trim().is_empty())
+                    .ok_or_else(|| format!("Invalid language pair {}, expected SOURCE/TARGET", value))?;
+                RouteCondition::Pair { source: source.trim().to_string(), target: target.trim().to_string() }
+            },
+            _ => return Err(format!("Unknown route condition: {} (supported: format, min-chars, pair)", kind))
+        };
+
+        let model = model.trim();
+        if model.is_empty() {
+            return Err(format!("Missing model in route {}", s));
+        }
+
+        Ok(RouteRule { condition, model: model.to_string() })
+    }
+}
+
+impl RouteRule {
+    fn matches(&self, req: &RouteRequest) -> bool {
+        match &self.condition {
+            RouteCondition::Format(format) => format == req.format,
+            RouteCondition::MinChars(chars) => req.chars >= *chars,
+            RouteCondition::Pair { source, target } => {
+                language_matches(source, req.source) && language_matches(target, req.target)
+            }
+        }
+    }
+}
+
+fn language_matches(pattern: &str, lang: &Language) -> bool {
+    pattern == "*" || pattern == lang.code || pattern == lang.internal_code
+}
+
+#[derive(Debug, Deserialize)]
+struct PairRoute {
+    source: String,
+    target: String,
+    model: String,
+}
+
+/// Routing table loaded from a TOML file:
+///
+/// ```toml
+/// default = "gemma3-4b"
+///
+/// [[routes]]
+/// source = "en"
+/// target = "ja"
+/// model = "gemma3-27b"
+/// ```
+#[derive(Debug, Deserialize)]
+struct RoutesFile {
+    default: Option<String>,
+    #[serde(default)]
+    routes: Vec<PairRoute>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Router {
+    rules: Vec<RouteRule>,
+    default: Option<String>,
+}
+
+impl Router {
+    pub fn new(rules: Vec<RouteRule>) -> Router {
+        Router { rules, default: None }
+    }
+
+    /// Appends the language pair routes of a routing table file to the rules
+    pub fn load_table(&mut self, path: &Path) -> Result<()> {
+        let data = std::fs::read_to_string(path)
+            .with_context(|| format!("Unable to read routes file: {}", path.display()))?;
+        let table: RoutesFile = toml::from_str(&data)
+            .with_context(|| format!("Invalid routes file: {}", path.display()))?;
+
+        self.rules.extend(table.routes.into_iter().map(|r| RouteRule {
+            condition: RouteCondition::Pair { source: r.source, target: r.target },
+            model: r.model,
+        }));
+        if table.default.is_some() {
+            self.default = table.default;
+        }
+        Ok(())
+    }
+
+    /// All the models this router can send requests to
+    pub fn models(&self) -> impl Iterator<Item = &String> {
+        self.rules.iter().map(|r| &r.model).chain(self.default.iter())
+    }
+
+    /// Returns the model of the first rule matching the request, or the routing table default
+    pub fn resolve(&self, req: &RouteRequest) -> Option<&str> {
+        self.rules.iter()
+            .find(|rule| rule.matches(req))
+            .map(|rule| rule.model.as_str())
+            .or(self.default.as_deref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::languages::get_language_from_code;
+
+    fn lang(code: &str) -> &'static Language {
+        get_language_from_code(&code.to_string()).unwrap()
+    }
+
+    fn request<'a>(format: &'a str, chars: usize, source: &str, target: &str) -> RouteRequest<'a> {
+        RouteRequest { format, chars, source: lang(source), target: lang(target) }
+    }
+
+    #[test]
+    fn parses_rules() {
+        let rule: RouteRule = "format:html=gemma3-27b".parse().unwrap();
+        assert!(matches!(rule.condition, RouteCondition::Format(ref f) if f == "html"));
+        assert_eq!(rule.model, "gemma3-27b");
+
+        let rule: RouteRule = "min-chars:2000=gemma3-12b".parse().unwrap();
+        assert!(matches!(rule.condition, RouteCondition::MinChars(2000)));
+
+        let rule: RouteRule = " pair: en / ja = gemma3-27b ".parse().unwrap();
+        assert!(matches!(rule.condition, RouteCondition::Pair { ref source, ref target } if source == "en" && target == "ja"));
+        assert_eq!(rule.model, "gemma3-27b");
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        let err = |s: &str| s.parse::<RouteRule>().unwrap_err();
+
+        assert_eq!(err("format:html"), "Invalid route format:html, expected CONDITION=MODEL");
+        assert_eq!(err("html=gemma3-27b"), "Invalid route condition html, expected KIND:VALUE");
+        assert_eq!(err("min-chars:many=gemma3-27b"), "Invalid number of characters: many");
+        assert_eq!(err("pair:en=gemma3-27b"), "Invalid language pair en, expected SOURCE/TARGET");
+        assert_eq!(err("pair:/ja=gemma3-27b"), "Invalid language pair /ja, expected SOURCE/TARGET");
+        assert_eq!(err("size:10=gemma3-27b"), "Unknown route condition: size (supported: format, min-chars, pair)");
+        assert_eq!(err("format:html= "), "Missing model in route format:html= ");
+    }
+
+    #[test]
+    fn resolves_first_matching_rule() {
+        let mut router = Router::new(vec![
+            "pair:en/ja=ja-model".parse().unwrap(),
+            "pair:*/zh-Hant=zt-model".parse().unwrap(),
+            "format:html=html-model".parse().unwrap(),
+            "min-chars:1000=large-model".parse().unwrap(),
+        ]);
+
+        assert_eq!(router.resolve(&request("html", 10, "en", "ja")), Some("ja-model"));
+        assert_eq!(router.resolve(&request("text", 10, "fr", "zt")), Some("zt-model"));
+        assert_eq!(router.resolve(&request("html", 10, "en", "fr")), Some("html-model"));
+        assert_eq!(router.resolve(&request("text", 1000, "en", "fr")), Some("large-model"));
+        assert_eq!(router.resolve(&request("text", 999, "en", "fr")), None);
+
+        router.default = Some("default-model".to_string());
+        assert_eq!(router.resolve(&request("text", 999, "en", "fr")), Some("default-model"));
+    }
+}
@@ -0,0 +1,257 @@
+use llama_cpp_2::model::LlamaModel;
+use llama_cpp_2::sampling::LlamaSampler;
+use serde::{Deserialize, Serialize};
+
+#[derive(clap::Args, Clone, Debug, Serialize, Deserialize)]
+pub struct SamplingConfig {
+    /// Sampling temperature (0 = greedy decoding)
+    #[arg(long, default_value_t = 0.0)]
+    pub temperature: f32,
+
+    /// Top-k sampling (0 = disabled)
+    #[arg(long, default_value_t = 40)]
+    pub top_k: i32,
+
+    /// Top-p (nucleus) sampling
+    #[arg(long, default_value_t = 0.95)]
+    pub top_p: f32,
+
+    /// Min-p sampling
+    #[arg(long, default_value_t = 0.05)]
+    pub min_p: f32,
+
+    /// Random seed used by the samplers
+    #[arg(long, default_value_t = 42)]
+    pub seed: u32,
+
+    /// Repetition penalty (1.0 = disabled)
+    #[arg(long, default_value_t = 1.0)]
+    pub repeat_penalty: f32,
+
+    /// Number of last tokens considered for the repetition penalty (-1 = context size)
+    #[arg(long, default_value_t = 64, allow_hyphen_values = true)]
+    pub repeat_last_n: i32,
+
+    /// DRY sampling multiplier (0 = disabled)
+    #[arg(long, default_value_t = 0.0)]
+    pub dry_multiplier: f32,
+
+    /// Maximum number of tokens to generate per request
+    #[arg(long)]
+    pub max_tokens: Option<u32>,
+
+    /// Upper limit for max_tokens, including the values set by requests
+    #[arg(long, default_value_t = 4096)]
+    pub max_tokens_limit: u32,
+}
+
+/// Sampling values recommended for a model, used unless set explicitly by the user
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RecommendedSampling {
+    pub temperature: Option<f32>,
+    pub top_k: Option<i32>,
+    pub top_p: Option<f32>,
+    pub min_p: Option<f32>,
+    pub repeat_penalty: Option<f32>,
+}
+
+/// Per-request overrides of the server sampling configuration
+#[derive(Clone, Debug, Default)]
+pub struct SamplingOverrides {
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub seed: Option<u32>,
+    pub max_tokens: Option<u32>,
+    pub repetition_penalty: Option<f32>,
+}
+
+impl SamplingConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(0.0..=2.0).contains(&self.temperature) {
+            return Err(format!("temperature must be between 0 and 2 (got {})", self.temperature));
+        }
+        if self.top_k < 0 {
+            return Err(format!("top_k must be positive (got {})", self.top_k));
+        }
+        if !(self.top_p > 0.0 && self.top_p <= 1.0) {
+            return Err(format!("top_p must be greater than 0 and at most 1 (got {})", self.top_p));
+        }
+        if !(0.0..=1.0).contains(&self.min_p) {
+            return Err(format!("min_p must be between 0 and 1 (got {})", self.min_p));
+        }
+        if !(self.repeat_penalty > 0.0 && self.repeat_penalty <= 2.0) {
+            return Err(format!("repetition_penalty must be greater than 0 and at most 2 (got {})", self.repeat_penalty));
+        }
+        if self.repeat_last_n < -1 {
+            return Err(format!("repeat_last_n must be -1 or greater (got {})", self.repeat_last_n));
+        }
+        if self.dry_multiplier < 0.0 {
+            return Err(format!("dry_multiplier must be positive (got {})", self.dry_multiplier));
+        }
+        if self.max_tokens == Some(0) {
+            return Err("max_tokens must be greater than 0".to_string());
+        }
+        if let Some(max_tokens) = self.max_tokens.filter(|m| *m > self.max_tokens_limit) {
+            return Err(format!("max_tokens must be at most {} (got {})", self.max_tokens_limit, max_tokens));
+        }
+        Ok(())
+    }
+
+    /// Applies the model recommendations to the values for which `is_default` returns true.
+    pub fn apply_recommended(&mut self, rec: &RecommendedSampling, is_default: impl Fn(&str) -> bool) {
+        if let Some(v) = rec.temperature.filter(|_| is_default("temperature")) {
+            self.temperature = v;
+        }
+        if let Some(v) = rec.top_k.filter(|_| is_default("top_k")) {
+            self.top_k = v;
+        }
+        if let Some(v) = rec.top_p.filter(|_| is_default("top_p")) {
+            self.top_p = v;
+        }
+        if let Some(v) = rec.min_p.filter(|_| is_default("min_p")) {
+            self.min_p = v;
+        }
+        if let Some(v) = rec.repeat_penalty.filter(|_| is_default("repeat_penalty")) {
+            self.repeat_penalty = v;
+        }
+    }
+
+    /// Returns a copy of this configuration with the request overrides applied.
+    /// The result is validated so that invalid request values can be reported to the caller.
+    pub fn with_overrides(&self, overrides: &SamplingOverrides) -> Result<SamplingConfig, String> {
+        let mut config = self.clone();
+        if let Some(v) = overrides.temperature {
+            config.temperature = v;
+        }
+        if let Some(v) = overrides.top_p {
+            config.top_p = v;
+        }
+        if let Some(v) = overrides.seed {
+            config.seed = v;
+        }
+        if let Some(v) = overrides.max_tokens {
+            config.max_tokens = Some(v);
+        }
+        if let Some(v) = overrides.repetition_penalty {
+            config.repeat_penalty = v;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn build_sampler(&self, model: &LlamaModel) -> LlamaSampler {
+        let seq_breakers = vec![b"\n", b":", b"\"", b"*"];
+
+        LlamaSampler::chain_simple([
+            LlamaSampler::penalties(self.repeat_last_n, self.repeat_penalty, 0.0, 0.0),
+            LlamaSampler::dry(model, self.dry_multiplier, 1.75, 2, -1, seq_breakers),
+            LlamaSampler::top_k(self.top_k),
+            LlamaSampler::typical(1.0, 0),
+            LlamaSampler::top_p(self.top_p, 0),
+            LlamaSampler::min_p(self.min_p, 0),
+            LlamaSampler::xtc(0.0, 0.1, 0, self.seed),
+            LlamaSampler::temp_ext(self.temperature, 0.0, 1.0),
+            LlamaSampler::dist(self.seed)
+        ])
+    }
+}
+
+/// Maximum number of tokens we allow a translation of `input_tokens` tokens to produce.
+pub fn output_token_budget(input_tokens: usize, ratio: f32) -> u32 {
+    ((input_tokens as f32 * ratio).ceil() as u32).saturating_add(32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        sampling: SamplingConfig,
+    }
+
+    fn defaults() -> SamplingConfig {
+        Cli::parse_from(["ltengine"]).sampling
+    }
+
+    fn override_error(overrides: SamplingOverrides) -> String {
+        defaults().with_overrides(&overrides).unwrap_err()
+    }
+
+    #[test]
+    fn accepts_defaults() {
+        assert!(defaults().validate().is_ok());
+    }
+
+    #[test]
+    fn validates_bounds() {
+        let config = |f: fn(&mut SamplingConfig)| {
+            let mut config = defaults();
+            f(&mut config);
+            config.validate()
+        };
+        assert!(config(|c| c.top_k = 0).is_ok());
+        assert!(config(|c| c.top_k = -1).unwrap_err().starts_with("top_k"));
+        assert!(config(|c| c.min_p = 1.5).unwrap_err().starts_with("min_p"));
+        assert!(config(|c| c.repeat_last_n = -1).is_ok());
+        assert!(config(|c| c.repeat_last_n = -2).unwrap_err().starts_with("repeat_last_n"));
+        assert!(config(|c| c.dry_multiplier = -0.5).unwrap_err().starts_with("dry_multiplier"));
+    }
+
+    #[test]
+    fn applies_overrides() {
+        let config = defaults().with_overrides(&SamplingOverrides {
+            temperature: Some(0.7),
+            top_p: Some(1.0),
+            seed: Some(7),
+            max_tokens: Some(4096),
+            repetition_penalty: Some(1.1),
+        }).unwrap();
+        assert_eq!((config.temperature, config.top_p, config.seed), (0.7, 1.0, 7));
+        assert_eq!((config.max_tokens, config.repeat_penalty), (Some(4096), 1.1));
+        // Values that cannot be overridden are kept
+        assert_eq!(config.top_k, 40);
+    }
+
+    #[test]
+    fn rejects_out_of_bounds_overrides() {
+        assert_eq!(
+            override_error(SamplingOverrides { temperature: Some(2.5), ..Default::default() }),
+            "temperature must be between 0 and 2 (got 2.5)"
+        );
+        assert!(override_error(SamplingOverrides { temperature: Some(-0.1), ..Default::default() }).starts_with("temperature"));
+        assert!(override_error(SamplingOverrides { top_p: Some(0.0), ..Default::default() }).starts_with("top_p"));
+        assert!(override_error(SamplingOverrides { top_p: Some(1.1), ..Default::default() }).starts_with("top_p"));
+        assert!(override_error(SamplingOverrides { repetition_penalty: Some(0.0), ..Default::default() }).starts_with("repetition_penalty"));
+        assert_eq!(
+            override_error(SamplingOverrides { max_tokens: Some(0), ..Default::default() }),
+            "max_tokens must be greater than 0"
+        );
+    }
+
+    #[test]
+    fn limits_max_tokens() {
+        assert_eq!(
+            override_error(SamplingOverrides { max_tokens: Some(4097), ..Default::default() }),
+            "max_tokens must be at most 4096 (got 4097)"
+        );
+
+        let mut config = defaults();
+        config.max_tokens_limit = 100;
+        let overrides = |max_tokens| SamplingOverrides { max_tokens: Some(max_tokens), ..Default::default() };
+        assert!(config.with_overrides(&overrides(100)).is_ok());
+        assert!(config.with_overrides(&overrides(101)).is_err());
+    }
+
+    #[test]
+    fn budgets_output_tokens() {
+        assert_eq!(output_token_budget(0, 1.5), 32);
+        assert_eq!(output_token_budget(10, 1.5), 47);
+        // Partial tokens are rounded up
+        assert_eq!(output_token_budget(3, 1.2), 36);
+        assert_eq!(output_token_budget(usize::MAX, 2.0), u32::MAX);
+    }
+}
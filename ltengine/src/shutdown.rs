@@ -0,0 +1,83 @@
+use std::sync::{Arc, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use actix_web::dev::ServerHandle;
+use actix_web::web;
+use tracing::{error, info, warn};
+use crate::health::{Health, ServerStatus};
+use crate::pool::ModelPool;
+
+/// Time given to the running translations to stop once the shutdown timeout has passed
+pub const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+/// Stops the server gracefully on the first SIGINT or SIGTERM, immediately on the second
+struct Shutdown {
+    server: ServerHandle,
+    /// Weak so that the signal handlers do not keep the models alive after the server stopped
+    pool: Weak<ModelPool>,
+    health: Arc<Health>,
+    timeout: Duration,
+    started: AtomicBool,
+}
+
+impl Shutdown {
+    async fn run(self: Arc<Self>) {
+        if self.started.swap(true, Ordering::SeqCst) {
+            warn!("Forcing shutdown");
+            self.server.stop(false).await;
+            return;
+        }
+
+        info!("Shutting down");
+        self.health.set_status(ServerStatus::ShuttingDown);
+
+        // The queues are drained and closed while the clients are still connected,
+        // so that the translations that cannot complete get a proper error
+        let pool = self.pool.clone();
+        let timeout = self.timeout;
+        let drained = web::block(move || {
+            let Some(pool) = pool.upgrade() else {
+                return true;
+            };
+            let drained = pool.drain(timeout);
+            pool.close(SHUTDOWN_GRACE);
+            drained
+        }).await;
+        if !matches!(drained, Ok(true)) {
+            warn!("Some translations did not complete within --shutdown-timeout");
+        }
+
+        self.server.stop(true).await;
+    }
+}
+
+/// Shuts `server` down on SIGINT or SIGTERM: new translations are refused, the queued ones
+/// have up to `timeout` to complete, then the remaining ones are cut short
+pub fn stop_on_signals(server: ServerHandle, pool: &Arc<ModelPool>, health: Arc<Health>, timeout: Duration) {
+    let shutdown = Arc::new(Shutdown { server, pool: Arc::downgrade(pool), health, timeout, started: AtomicBool::new(false) });
+
+    {
+        let shutdown = shutdown.clone();
+        actix_web::rt::spawn(async move {
+            while actix_web::rt::signal::ctrl_c().await.is_ok() {
+                actix_web::rt::spawn(shutdown.clone().run());
+            }
+        });
+    }
+
+    #[cfg(unix)]
+    actix_web::rt::spawn(async move {
+        use actix_web::rt::signal::unix::{signal, SignalKind};
+
+        let mut terminate = match signal(SignalKind::terminate()) {
+            Ok(terminate) => terminate,
+            Err(err) => {
+                error!("Unable to listen for SIGTERM: {}", err);
+                return;
+            }
+        };
+        while terminate.recv().await.is_some() {
+            actix_web::rt::spawn(shutdown.clone().run());
+        }
+    });
+}
@@ -0,0 +1,316 @@
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use crate::languages::get_language_from_code;
+
+/// Better translation submitted by a user through `/suggest`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Suggestion {
+    pub id: u64,
+    /// Original text
+    pub q: String,
+    /// Suggested translation
+    pub s: String,
+    pub source: String,
+    pub target: String,
+    /// Reviewed by an admin, approved suggestions are used by the translation memory
+    #[serde(default)]
+    pub approved: bool,
+    /// Submission time (unix timestamp)
+    #[serde(default)]
+    pub created: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// One JSON object per line (`source`, `target`, `q`, `s`), for fine-tuning
+    #[default]
+    Jsonl,
+    /// Translation Memory eXchange, for CAT tools
+    Tmx,
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Jsonl => "application/jsonl",
+            ExportFormat::Tmx => "application/x-tmx+xml",
+        }
+    }
+}
+
+struct Suggestions {
+    list: Vec<Suggestion>,
+    next_id: u64,
+    /// Approved translations by (source, target, text)
+    memory: HashMap<(String, String, String), String>,
+}
+
+impl Suggestions {
+    fn new(list: Vec<Suggestion>) -> Suggestions {
+        let next_id = list.iter().map(|s| s.id + 1).max().unwrap_or(1);
+        let mut suggestions = Suggestions { list, next_id, memory: HashMap::new() };
+        suggestions.index();
+        suggestions
+    }
+
+    fn index(&mut self) {
+        // Later suggestions win
+        self.memory = self.list.iter()
+            .filter(|s| s.approved)
+            .map(|s| ((public_code(&s.source), public_code(&s.target), s.q.trim().to_string()), s.s.clone()))
+            .collect();
+    }
+}
+
+/// Public code of a language, suggestions saved by older versions may use aliases (e.g. `zh`)
+fn public_code(code: &str) -> String {
+    get_language_from_code(&code.to_string()).map_or_else(|| code.to_string(), |l| l.code.to_string())
+}
+
+/// Suggestions persisted in a JSON lines file, new ones are appended
+pub struct SuggestionStore {
+    path: PathBuf,
+    suggestions: RwLock<Suggestions>,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+impl SuggestionStore {
+    /// Loads the suggestions from `path`, starting with none if the file does not exist yet
+    pub fn load(path: &Path) -> Result<SuggestionStore> {
+        let mut list = Vec::new();
+        if path.exists() {
+            let data = std::fs::read_to_string(path)
+                .with_context(|| format!("Unable to read suggestions file: {}", path.display()))?;
+            for (i, line) in data.lines().enumerate().filter(|(_, l)| !l.trim().is_empty()) {
+                let suggestion: Suggestion = serde_json::from_str(line)
+                    .with_context(|| format!("Invalid suggestions file: {} (line {})", path.display(), i + 1))?;
+                list.push(suggestion);
+            }
+        }
+
+        Ok(SuggestionStore {
+            path: path.to_path_buf(),
+            suggestions: RwLock::new(Suggestions::new(list)),
+        })
+    }
+
+    fn save(&self, list: &[Suggestion]) -> Result<()> {
+        let mut data = String::new();
+        for suggestion in list {
+            data.push_str(&serde_json::to_string(suggestion)?);
+            data.push('\n');
+        }
+
+        // Write to a temporary file first so that the store is never left half written
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, data)
+            .with_context(|| format!("Unable to write suggestions file: {}", tmp.display()))?;
+        std::fs::rename(&tmp, &self.path)
+            .with_context(|| format!("Unable to write suggestions file: {}", self.path.display()))?;
+        Ok(())
+    }
+
+    pub fn add(&self, q: &str, s: &str, source: &str, target: &str) -> Result<Suggestion> {
+        let mut suggestions = self.suggestions.write().unwrap();
+        let suggestion = Suggestion {
+            id: suggestions.next_id,
+            q: q.to_string(),
+            s: s.to_string(),
+            source: source.to_string(),
+            target: target.to_string(),
+            approved: false,
+            created: now(),
+        };
+
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)
+            .with_context(|| format!("Unable to write suggestions file: {}", self.path.display()))?;
+        writeln!(file, "{}", serde_json::to_string(&suggestion)?)
+            .with_context(|| format!("Unable to write suggestions file: {}", self.path.display()))?;
+
+        suggestions.next_id += 1;
+        suggestions.list.push(suggestion.clone());
+        Ok(suggestion)
+    }
+
+    pub fn list(&self) -> Vec<Suggestion> {
+        self.suggestions.read().unwrap().list.clone()
+    }
+
+    pub fn approve(&self, id: u64) -> Result<Suggestion> {
+        let mut suggestions = self.suggestions.write().unwrap();
+        let suggestion = suggestions.list.iter_mut()
+            .find(|s| s.id == id)
+            .ok_or_else(|| anyhow!("Unknown suggestion: {}", id))?;
+        suggestion.approved = true;
+        let approved = suggestion.clone();
+
+        self.save(&suggestions.list)?;
+        suggestions.index();
+        Ok(approved)
+    }
+
+    pub fn delete(&self, id: u64) -> Result<()> {
+        let mut suggestions = self.suggestions.write().unwrap();
+        let len = suggestions.list.len();
+        suggestions.list.retain(|s| s.id != id);
+        if suggestions.list.len() == len {
+            return Err(anyhow!("Unknown suggestion: {}", id));
+        }
+
+        self.save(&suggestions.list)?;
+        suggestions.index();
+        Ok(())
+    }
+
+    /// Approved translation of `q` (`source` and `target` are public codes), if any
+    pub fn lookup(&self, q: &str, source: &str, target: &str) -> Option<String> {
+        self.suggestions.read().unwrap()
+            .memory
+            .get(&(source.to_string(), target.to_string(), q.trim().to_string()))
+            .cloned()
+    }
+
+    pub fn export(&self, format: ExportFormat, approved_only: bool) -> String {
+        let suggestions = self.suggestions.read().unwrap();
+        let selected = suggestions.list.iter().filter(|s| s.approved || !approved_only);
+
+        match format {
+            ExportFormat::Jsonl => selected
+                .map(|s| serde_json::json!({ "source": s.source, "target": s.target, "q": s.q, "s": s.s }).to_string() + "\n")
+                .collect(),
+            ExportFormat::Tmx => {
+                let mut out = String::from(concat!(
+                    "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+                    "<tmx version=\"1.4\">\n",
+                    "  <header creationtool=\"ltengine\" creationtoolversion=\"", env!("CARGO_PKG_VERSION"), "\" ",
+                    "segtype=\"sentence\" o-tmf=\"ltengine\" adminlang=\"en\" srclang=\"*all*\" datatype=\"plaintext\"/>\n",
+                    "  <body>\n"
+                ));
+                for s in selected {
+                    out.push_str(&format!(
+                        "    <tu tuid=\"{}\">\n      <tuv xml:lang=\"{}\"><seg>{}</seg></tuv>\n      <tuv xml:lang=\"{}\"><seg>{}</seg></tuv>\n    </tu>\n",
+                        s.id, escape_xml(&s.source), escape_xml(&s.q), escape_xml(&s.target), escape_xml(&s.s)
+                    ));
+                }
+                out.push_str("  </body>\n</tmx>\n");
+                out
+            }
+        }
+    }
+
+    pub fn print(&self) {
+        println!("{:<6} {:<8} {:<6} {:<6} TEXT -> SUGGESTION", "ID", "APPROVED", "SOURCE", "TARGET");
+        for s in self.suggestions.read().unwrap().list.iter() {
+            println!("{:<6} {:<8} {:<6} {:<6} {} -> {}",
+                s.id,
+                if s.approved { "yes" } else { "no" },
+                s.source,
+                s.target,
+                s.q.replace('\n', " "),
+                s.s.replace('\n', " ")
+            );
+        }
+    }
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Store in a new temporary file
+    fn store() -> (SuggestionStore, PathBuf) {
+        let path = std::env::temp_dir().join(format!("ltengine-suggestions-{}.jsonl", uuid::Uuid::new_v4()));
+        (SuggestionStore::load(&path).unwrap(), path)
+    }
+
+    #[test]
+    fn persists_suggestions() {
+        let (store, path) = store();
+        assert_eq!(store.add("Hello", "Bonjour", "en", "fr").unwrap().id, 1);
+        assert_eq!(store.add("Bye", "Au revoir", "en", "fr").unwrap().id, 2);
+        store.approve(2).unwrap();
+        store.delete(1).unwrap();
+
+        let reloaded = SuggestionStore::load(&path).unwrap();
+        let list = reloaded.list();
+        assert_eq!(list.len(), 1);
+        assert_eq!((list[0].id, list[0].s.as_str(), list[0].approved), (2, "Au revoir", true));
+        assert_eq!(reloaded.add("Yes", "Oui", "en", "fr").unwrap().id, 3);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn rejects_unknown_ids() {
+        let (store, _) = store();
+        assert!(store.approve(7).is_err());
+        assert!(store.delete(7).is_err());
+    }
+
+    #[test]
+    fn looks_up_approved_suggestions() {
+        let (store, path) = store();
+        store.add("Hello", "Bonjour", "en", "fr").unwrap();
+        assert_eq!(store.lookup("Hello", "en", "fr"), None);
+
+        store.approve(1).unwrap();
+        store.add("Hello", "Salut", "en", "fr").unwrap();
+        store.approve(2).unwrap();
+        assert_eq!(store.lookup(" Hello\n", "en", "fr").as_deref(), Some("Salut"));
+        assert_eq!(store.lookup("Hello", "en", "es"), None);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn looks_up_aliases_by_public_code() {
+        let (store, path) = store();
+        store.add("Hello", "你好", "en", "zh").unwrap();
+        store.approve(1).unwrap();
+        assert_eq!(store.lookup("Hello", "en", "zh-Hans").as_deref(), Some("你好"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn exports_jsonl() {
+        let (store, path) = store();
+        store.add("Hello", "Bonjour", "en", "fr").unwrap();
+        store.add("Bye", "Au revoir", "en", "fr").unwrap();
+        store.approve(2).unwrap();
+
+        assert_eq!(store.export(ExportFormat::Jsonl, false).lines().count(), 2);
+        let approved = store.export(ExportFormat::Jsonl, true);
+        let line: serde_json::Value = serde_json::from_str(approved.trim()).unwrap();
+        assert_eq!(line, serde_json::json!({ "source": "en", "target": "fr", "q": "Bye", "s": "Au revoir" }));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn exports_escaped_tmx() {
+        let (store, path) = store();
+        store.add("a < b & \"c\"", "a > b", "en", "fr").unwrap();
+
+        let tmx = store.export(ExportFormat::Tmx, false);
+        assert!(tmx.starts_with("<?xml"));
+        assert!(tmx.contains("<tuv xml:lang=\"en\"><seg>a &lt; b &amp; &quot;c&quot;</seg></tuv>"));
+        assert!(tmx.contains("<tuv xml:lang=\"fr\"><seg>a &gt; b</seg></tuv>"));
+        assert!(tmx.trim_end().ends_with("</tmx>"));
+        assert!(!store.export(ExportFormat::Tmx, true).contains("<tu "));
+        std::fs::remove_file(path).unwrap();
+    }
+}
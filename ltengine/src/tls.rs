@@ -0,0 +1,96 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use anyhow::{anyhow, Context, Result};
+use rustls::ServerConfig;
+use rustls::crypto::ring::{default_provider, sign::any_supported_type};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use tracing::{error, info};
+
+/// Serves the certificate loaded from `cert_path`/`key_path`,
+/// which can be reloaded without restarting the server
+#[derive(Debug)]
+pub struct CertResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    key: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertResolver {
+    pub fn load(cert_path: &Path, key_path: &Path) -> Result<Arc<CertResolver>> {
+        Ok(Arc::new(CertResolver {
+            cert_path: cert_path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+            key: RwLock::new(Arc::new(load_key(cert_path, key_path)?)),
+        }))
+    }
+
+    /// Reads the certificate and key again. The current ones are kept if they are invalid.
+    pub fn reload(&self) -> Result<()> {
+        let key = load_key(&self.cert_path, &self.key_path)?;
+        *self.key.write().unwrap() = Arc::new(key);
+        Ok(())
+    }
+
+    pub fn server_config(self: &Arc<Self>) -> Result<ServerConfig> {
+        Ok(ServerConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone()))
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.key.read().unwrap().clone())
+    }
+}
+
+fn load_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey> {
+    let mut reader = BufReader::new(File::open(cert_path)
+        .with_context(|| format!("Unable to read TLS certificate: {}", cert_path.display()))?);
+    let certs = rustls_pemfile::certs(&mut reader)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Invalid TLS certificate: {}", cert_path.display()))?;
+    if certs.is_empty() {
+        return Err(anyhow!("No certificate found in {}", cert_path.display()));
+    }
+
+    let mut reader = BufReader::new(File::open(key_path)
+        .with_context(|| format!("Unable to read TLS key: {}", key_path.display()))?);
+    let key = rustls_pemfile::private_key(&mut reader)
+        .with_context(|| format!("Invalid TLS key: {}", key_path.display()))?
+        .ok_or_else(|| anyhow!("No private key found in {}", key_path.display()))?;
+    let signing_key = any_supported_type(&key)
+        .map_err(|err| anyhow!("Unsupported TLS key {}: {}", key_path.display(), err))?;
+
+    let key = CertifiedKey::new(certs, signing_key);
+    key.keys_match()
+        .map_err(|err| anyhow!("TLS key {} does not match the certificate: {}", key_path.display(), err))?;
+    Ok(key)
+}
+
+/// Reloads the certificate of `resolver` every time the process receives SIGHUP
+#[cfg(unix)]
+pub fn reload_on_sighup(resolver: Arc<CertResolver>) {
+    use actix_web::rt::signal::unix::{signal, SignalKind};
+
+    actix_web::rt::spawn(async move {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(err) => {
+                error!("Unable to listen for SIGHUP, certificates will not be reloaded: {}", err);
+                return;
+            }
+        };
+
+        while hangup.recv().await.is_some() {
+            match resolver.reload() {
+                Ok(()) => info!("Reloaded TLS certificate"),
+                Err(err) => error!("Failed to reload TLS certificate: {:#}", err),
+            }
+        }
+    });
+}